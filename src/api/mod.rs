@@ -1,9 +1,83 @@
+use serde::Serialize;
 use serde_derive::{Deserialize, Serialize};
 
+/// Audience of the tokens used to access the API.
+const ACCESS_AUDIENCE: &'static str = "access";
+/// Audience of the tokens used to renew access tokens.
+const REFRESH_AUDIENCE: &'static str = "refresh";
+/// Audience of the tokens standing in for API keys.
+const API_KEY_AUDIENCE: &'static str = "api-key";
+/// Header API keys are presented in, instead of the authorization header.
+const API_KEY_HEADER: &'static str = "X-Api-Key";
+/// Length of session ids and refresh nonces.
+const SESSION_ID_SIZE: usize = 32;
+/// Length of the id half of API keys.
+const API_KEY_ID_SIZE: usize = 16;
+/// Length of the secret half of API keys.
+const API_KEY_SECRET_SIZE: usize = 32;
+/// Number of wrong second factors a login challenge takes before giving up.
+const MAX_CHALLENGE_ATTEMPTS: u32 = 5;
+/// Number of entries listed per page, unless asked otherwise.
+const DEFAULT_PAGE_SIZE: usize = 20;
+/// Largest number of entries listed per page.
+const MAX_PAGE_SIZE: usize = 100;
+/// Number of history entries read at a time when looking for those passing
+/// the filters of a page.
+const HISTORY_BATCH_SIZE: usize = 100;
+/// Largest number of history entries looked through for a single page. Pages
+/// that come up short of it get a cursor to keep looking from.
+const MAX_HISTORY_SCAN: usize = 1000;
+/// Longest user agent kept around for a session, in characters.
+const MAX_USER_AGENT_SIZE: usize = 256;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Token {
     username: String,
-    is_admin: bool,
+    /// Id of the session this token belongs to.
+    jti: String,
+    aud: String,
+    iat: i64,
+    exp: i64,
+    /// The API key the request was made with, if it wasn't made with a token.
+    #[serde(skip)]
+    api_key: Option<db::ApiKey>,
+}
+impl Token {
+    /// Keeps API keys without a scope passing the check out of the route.
+    /// Tokens from logging in can go anywhere.
+    fn require_scope<F>(&self, check: F) -> Result<(), JsonValue>
+    where
+        F: Fn(&db::ApiScope) -> bool,
+    {
+        let allowed = match self.api_key {
+            Some(ref key) => key.scopes.iter().any(check),
+            None => true,
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(JsonResponse::error(
+                "this API key is not allowed to do this",
+            ))
+        }
+    }
+
+    /// Keeps API keys out of the route altogether.
+    fn require_session(&self) -> Result<(), JsonValue> {
+        self.require_scope(|_| false)
+    }
+}
+
+/// Claims of a refresh token. Each session only has one valid refresh token at
+/// any given time, told apart from the ones rotated out before it by its nonce.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshToken {
+    username: String,
+    jti: String,
+    nonce: String,
+    aud: String,
+    iat: i64,
+    exp: i64,
 }
 
 /// Repesents an ammount of money.
@@ -18,16 +92,24 @@ pub struct Transfer {
     ammount: Balance,
 }
 
-use super::settings::Auth;
+use super::settings::{self, Permission, Role, Settings};
 
 use crate::db;
 use crate::keyhash;
+use crate::mail;
+use crate::naming;
+use crate::signing;
 use crate::state;
+use crate::totp;
+use chrono::Utc;
+use ring::constant_time;
 use rocket::http::{ContentType, Status};
-use rocket::request::{FromRequest, Outcome, Request};
+use rocket::request::{Form, FromRequest, Outcome, Request};
 use rocket::response::{self, content, Responder};
 use rocket::{Response, State};
 use rocket_contrib::json::{Json, JsonValue};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
 
 mod objs;
 use objs::*;
@@ -111,266 +193,2056 @@ pub fn home<'a>() -> Response<'a> {
         .finalize()
 }
 
+/// Public keys our tokens can be verified with, for other services to use.
+#[get("/.well-known/jwks.json")]
+pub fn jwks(server: State<state::Server>) -> Json<JsonValue> {
+    Json(json!({ "keys": server.keys.jwks() }))
+}
+
 #[get("/info")]
 pub fn info(server: State<state::Server>, token: Token) -> JsonResponse {
+    token.require_scope(|scope| *scope == db::ApiScope::ReadOnly)?;
     let mut conn = (*server).db_conn.borrow();
     let info = db::user_info(&mut conn, &token.username).map_err(|e| {
-        eprintln!("Error getting user info for {}: {}", &token.username, e);
-        return JsonResponse::error("internal server error");
+        error!("Error getting user info for {}: {}", token.username, e);
+        JsonResponse::error("internal server error")
     })?;
     JsonResponse::Success(json!({
         "realname": info.realname,
         "username": info.username,
+        "email": info.email,
+        "email_verified": info.email_verified,
+        "created": info.created,
         "balance": info.balance,
-        "is_admin": info.is_admin
+        "is_admin": info.is_admin,
+        "roles": info.roles,
+        "two_factor": info.two_factor,
+        "frozen": info.frozen.is_some()
     }))
 }
 
 #[post("/login", format = "json", data = "<param>")]
-pub fn login(server: State<state::Server>, param: Json<LoginRequest>) -> JsonResponse {
-    use jwt::{encode, Header};
-
+pub fn login(
+    server: State<state::Server>,
+    client: ClientIp,
+    agent: UserAgent,
+    param: Json<LoginRequest>,
+) -> JsonResponse {
     let srv: &state::Server = &server;
     let mut conn = srv.db_conn.borrow();
+    let LoginRequest { username, key } = param.into_inner();
 
-    let valid = db::validate(&mut *conn, param.0.username.clone(), param.0.key).map_err(|e| {
-        error!("Error validating login credentials: {}", e);
-        JsonResponse::error("internal server error")
-    })?;
+    let policy = &server.settings.lockout;
+    let mut subjects = vec![(db::LockoutKind::User, username.clone())];
+    if let ClientIp(Some(ip)) = client {
+        subjects.push((db::LockoutKind::Ip, ip.to_string()));
+    }
+    if policy.enabled {
+        check_lockouts(&mut *conn, &subjects)?;
+    }
+
+    let valid = check_key(&mut *conn, &server, &username, key)?;
     if !valid {
+        if policy.enabled {
+            count_failed_login(&mut *conn, policy, &subjects)?;
+        }
         return JsonResponse::fail("invalid username or password");
     }
+    let username = db::canonical_username(&mut *conn, &username)
+        .map_err(|e| {
+            error!("Error getting username of {}: {}", username, e);
+            JsonResponse::error("internal server error")
+        })?
+        .unwrap_or(username);
 
-    let auth = &(*server).settings.auth;
-    let admin = db::is_admin(&mut *conn, param.0.username.clone()).map_err(|e| {
-        error!("Error verifying admin status: {}", e);
+    let totp = db::totp_state(&mut *conn, &username).map_err(|e| {
+        error!("Error getting two-factor state of {}: {}", username, e);
         JsonResponse::error("internal server error")
     })?;
+    if totp.map(|totp| totp.enabled).unwrap_or(false) {
+        let challenge = random_string(SESSION_ID_SIZE);
+        db::create_challenge(
+            &mut *conn,
+            &challenge,
+            &username,
+            server.settings.two_factor.challenge_lifetime,
+        )
+        .map_err(|e| {
+            error!("Error creating login challenge: {}", e);
+            JsonResponse::error("internal server error")
+        })?;
 
-    let token = encode(
-        &Header::new(auth.algorithm),
-        &Token {
-            username: param.0.username,
-            is_admin: admin,
-        },
-        auth.secret.as_bytes(),
-    )
-    .map_err(|_| JsonResponse::error("failed to sign token"))?;
-    JsonResponse::Success(json!({ "token": token }))
+        /* Failed logins are only forgotten once the second factor is in. */
+        return JsonResponse::Success(json!({
+            "two_factor": true,
+            "challenge": challenge
+        }));
+    }
+    if policy.enabled {
+        forget_failed_logins(&mut *conn, &username)?;
+    }
+
+    JsonResponse::Success(issue_tokens(&mut *conn, &server, username, client, agent)?)
 }
 
-#[post("/register", format = "json", data = "<param>")]
-pub fn register(server: State<state::Server>, param: Json<RegisterRequest>) -> JsonResponse {
+#[post("/login/2fa", format = "json", data = "<param>")]
+pub fn login_two_factor(
+    server: State<state::Server>,
+    client: ClientIp,
+    agent: UserAgent,
+    param: Json<ChallengeRequest>,
+) -> JsonResponse {
     let mut conn = (*server).db_conn.borrow();
-    let param = &(*param);
-
-    let (keyhash, salt) = keyhash::generate(param.key.clone());
+    let internal = |e: redis::RedisError| {
+        error!("Error answering login challenge: {}", e);
+        JsonResponse::error("internal server error")
+    };
 
-    info!("Creating an account for {}", param.username);
-    let status = match db::create_account(
-        &mut *conn,
-        param.username.clone(),
-        param.username.clone(),
-        param.name.clone(),
-        keyhash,
-        salt,
-    ) {
-        Ok(status) => status,
-        Err(what) => {
-            error!("Error when contacting Redis: {:?}", what);
-            return JsonResponse::fail("internal server error");
-        }
+    let username = match db::challenge_username(&mut *conn, &param.challenge).map_err(internal)? {
+        Some(username) => username,
+        None => return JsonResponse::fail("invalid or expired challenge"),
     };
-    debug!(
-        "Account creation invoke for {} returned {:?}",
-        param.username, status
-    );
 
-    match status.as_str() {
-        "-KeyExists" => JsonResponse::fail("user already exists"),
-        "+OK" => {
-            use jwt::{encode, Header};
-
-            let auth = &(*server).settings.auth;
-            let token = encode(
-                &Header::new(auth.algorithm),
-                &Token {
-                    username: param.username.clone(),
-                    is_admin: false,
-                },
-                auth.secret.as_bytes(),
-            )
-            .map_err(|_| JsonResponse::error("failed to sign token"))?;
-            JsonResponse::Success(json!({ "token": token }))
+    /* Wrong codes count as failed logins, or else anyone with the password
+     * could keep asking for new challenges and guessing away. */
+    let policy = &server.settings.lockout;
+    let mut subjects = vec![(db::LockoutKind::User, username.clone())];
+    if let ClientIp(Some(ip)) = client {
+        subjects.push((db::LockoutKind::Ip, ip.to_string()));
+    }
+    if policy.enabled {
+        check_lockouts(&mut *conn, &subjects)?;
+    }
+
+    if !check_second_factor(&mut *conn, &username, &param.factor)? {
+        if policy.enabled {
+            count_failed_login(&mut *conn, policy, &subjects)?;
         }
-        s @ &_ => {
-            error!("Invalid return from account creation invoke: {}", s);
-            JsonResponse::fail("internal server error")
+        let attempts = match db::fail_challenge(&mut *conn, &param.challenge).map_err(internal)? {
+            Some(attempts) => attempts,
+            None => return JsonResponse::fail("invalid or expired challenge"),
+        };
+        if attempts >= MAX_CHALLENGE_ATTEMPTS {
+            warn!("Too many failed second factors for {}", username);
+            db::drop_challenge(&mut *conn, &param.challenge).map_err(internal)?;
         }
+        return JsonResponse::fail("invalid code");
     }
+    db::drop_challenge(&mut *conn, &param.challenge).map_err(internal)?;
+    if policy.enabled {
+        forget_failed_logins(&mut *conn, &username)?;
+    }
+
+    JsonResponse::Success(issue_tokens(&mut *conn, &server, username, client, agent)?)
 }
 
-#[post("/drop", format = "json", data = "<param>")]
-pub fn drop(token: Token, param: Json<DropRequest>) -> JsonResponse {
-    unimplemented!()
+#[post("/2fa/enroll")]
+pub fn totp_enroll(server: State<state::Server>, token: Token) -> JsonResponse {
+    token.require_session()?;
+    let mut conn = (*server).db_conn.borrow();
+    let internal = |e: redis::RedisError| {
+        error!("Error enrolling {} in two-factor: {}", token.username, e);
+        JsonResponse::error("internal server error")
+    };
+
+    let state = db::totp_state(&mut *conn, &token.username).map_err(internal)?;
+    if state.map(|state| state.enabled).unwrap_or(false) {
+        return JsonResponse::fail("two-factor authentication is already enabled");
+    }
+
+    let secret = totp::generate_secret();
+    db::set_pending_totp(&mut *conn, &token.username, &secret).map_err(internal)?;
+
+    let uri = totp::provisioning_uri(&server.settings.two_factor.issuer, &token.username, &secret);
+    JsonResponse::Success(json!({ "secret": secret, "uri": uri }))
 }
 
-#[post("/transfer", format = "json", data = "<param>")]
-pub fn transfer(
+#[post("/2fa/confirm", format = "json", data = "<param>")]
+pub fn totp_confirm(
     server: State<state::Server>,
     token: Token,
-    param: Json<TransferRequest>,
+    param: Json<TotpConfirmRequest>,
 ) -> JsonResponse {
+    token.require_session()?;
     let mut conn = (*server).db_conn.borrow();
+    let internal = |e: redis::RedisError| {
+        error!("Error confirming two-factor of {}: {}", token.username, e);
+        JsonResponse::error("internal server error")
+    };
 
-    if token.username == param.0.to {
-        return JsonResponse::fail("you cannot make transfers to yourself");
+    let secret = match db::totp_state(&mut *conn, &token.username).map_err(internal)? {
+        Some(ref state) if state.enabled => {
+            return JsonResponse::fail("two-factor authentication is already enabled")
+        }
+        Some(state) => state.secret,
+        None => return JsonResponse::fail("there is no enrollment to confirm"),
+    };
+    let step = match totp::verify(&secret, &param.code, Utc::now().timestamp() as u64) {
+        Some(step) => step,
+        None => return JsonResponse::fail("invalid code"),
+    };
+
+    let codes = totp::generate_recovery_codes(server.settings.two_factor.recovery_codes);
+    let hashes = codes
+        .iter()
+        .map(|code| totp::hash_recovery_code(code))
+        .collect::<Vec<_>>();
+    db::enable_totp(&mut *conn, &token.username, step, &hashes[..]).map_err(internal)?;
+
+    info!("Enabled two-factor authentication for {}", token.username);
+    JsonResponse::Success(json!({ "recovery_codes": codes }))
+}
+
+#[post("/2fa/disable", format = "json", data = "<param>")]
+pub fn totp_disable(
+    server: State<state::Server>,
+    token: Token,
+    param: Json<TotpDisableRequest>,
+) -> JsonResponse {
+    token.require_session()?;
+    let mut conn = (*server).db_conn.borrow();
+    let param = param.into_inner();
+
+    if !check_key(&mut *conn, &server, &token.username, param.key)? {
+        return JsonResponse::fail("invalid password");
+    }
+    if !check_second_factor(&mut *conn, &token.username, &param.factor)? {
+        return JsonResponse::fail("invalid code");
     }
 
-    let r =
-        db::transaction(&mut conn, &token.username, &param.0.to, param.0.amount).map_err(|e| {
-            eprintln!("Transaction error: {}", e);
-            return JsonResponse::error("internal server error");
-        })?;
+    db::disable_totp(&mut *conn, &token.username).map_err(|e| {
+        error!("Error disabling two-factor of {}: {}", token.username, e);
+        JsonResponse::error("internal server error")
+    })?;
 
-    use db::TransactionStatus;
+    info!("Disabled two-factor authentication for {}", token.username);
+    JsonResponse::empty_success()
+}
 
-    match r {
-        TransactionStatus::Success => JsonResponse::empty_success(),
-        TransactionStatus::NotEnoughFunds => JsonResponse::fail("not enough funds"),
-        TransactionStatus::InvalidFrom => {
-            JsonResponse::fail("invalid source user (we're as confused as you right now)")
-        }
-        TransactionStatus::InvalidTo => JsonResponse::fail("invalid destination user"),
-        TransactionStatus::Cooldown => {
-            JsonResponse::fail("please wait before performing this action")
+#[post("/token/refresh", format = "json", data = "<param>")]
+pub fn refresh(server: State<state::Server>, param: Json<RefreshRequest>) -> JsonResponse {
+    let auth = &(*server).settings.auth;
+    let claims = match server
+        .keys
+        .verify::<RefreshToken>(&param.refresh_token, REFRESH_AUDIENCE)
+    {
+        Some(claims) => claims,
+        None => return JsonResponse::fail("invalid refresh token"),
+    };
+
+    let mut conn = (*server).db_conn.borrow();
+    let now = Utc::now().timestamp();
+    let renewed = db::Session {
+        nonce: random_string(SESSION_ID_SIZE),
+        expires: now + auth.refresh_lifetime as i64,
+        ..Default::default()
+    };
+
+    let status = db::refresh_session(
+        &mut *conn,
+        &claims.username,
+        &claims.jti,
+        &claims.nonce,
+        &renewed,
+    )
+    .map_err(|e| {
+        error!("Error refreshing session for {}: {}", claims.username, e);
+        JsonResponse::error("internal server error")
+    })?;
+
+    use db::RefreshStatus;
+    match status {
+        RefreshStatus::Success => (),
+        RefreshStatus::Revoked => return JsonResponse::fail("session is no longer valid"),
+        RefreshStatus::Reused => {
+            warn!(
+                "Refresh token for session {} of {} was reused, revoking the session",
+                claims.jti, claims.username
+            );
+            return JsonResponse::fail("session is no longer valid");
         }
     }
+
+    JsonResponse::Success(sign_tokens(
+        &server,
+        claims.username,
+        claims.jti,
+        renewed,
+        now,
+    )?)
+}
+
+#[post("/logout")]
+pub fn logout(server: State<state::Server>, token: Token) -> JsonResponse {
+    token.require_session()?;
+    let mut conn = (*server).db_conn.borrow();
+    db::revoke_session(&mut *conn, &token.username, &token.jti).map_err(|e| {
+        error!("Error revoking session for {}: {}", token.username, e);
+        JsonResponse::error("internal server error")
+    })?;
+    JsonResponse::empty_success()
 }
 
-#[get("/history")]
-pub fn history(server: State<state::Server>, token: Token) -> JsonResponse {
+#[get("/sessions")]
+pub fn sessions(server: State<state::Server>, token: Token) -> JsonResponse {
+    token.require_session()?;
+
     let mut conn = (*server).db_conn.borrow();
-    let h = db::history(&mut conn, &token.username).map_err(|e| {
-        eprintln!("Error getting history: {}", e);
-        return JsonResponse::error("internal server error");
+    let sessions = db::list_sessions(&mut *conn, &token.username).map_err(|e| {
+        error!("Error listing sessions of {}: {}", token.username, e);
+        JsonResponse::error("internal server error")
     })?;
 
-    let res: Vec<_> = h
+    let sessions = sessions
         .into_iter()
-        .map(|e| serde_json::from_str::<HistoryEntry>(&e))
-        .collect::<Result<Vec<_>, _>>()
-        .map_err(|e| {
-            eprintln!("Error deserializing history entries: {}", e);
-            return JsonResponse::error("internal server error");
-        })?;
-    JsonResponse::Success(json!({ "history": res }))
+        .map(|(id, session)| {
+            json!({
+                "current": id == token.jti,
+                "id": id,
+                "created": session.created,
+                "last_used": session.last_used,
+                "expires": session.expires,
+                "ip": session.ip,
+                "user_agent": session.user_agent
+            })
+        })
+        .collect::<Vec<_>>();
+    JsonResponse::Success(json!({ "sessions": sessions }))
 }
 
-#[post("/withdraw", format = "json", data = "<param>")]
-pub fn withdraw(
-    server: State<state::Server>,
-    token: Token,
-    param: Json<WithdrawRequest>,
-) -> JsonResponse {
+#[delete("/sessions/<id>")]
+pub fn revoke_session(server: State<state::Server>, token: Token, id: String) -> JsonResponse {
+    token.require_session()?;
+
     let mut conn = (*server).db_conn.borrow();
-    let success = db::withdraw(&mut conn, token.username, param.0.amount).map_err(|e| {
-        eprintln!("Error withdrawing: {}", e);
-        return JsonResponse::error("internal server error");
+    let revoked = db::revoke_session(&mut *conn, &token.username, &id).map_err(|e| {
+        error!("Error revoking session of {}: {}", token.username, e);
+        JsonResponse::error("internal server error")
     })?;
-    if !success {
-        return JsonResponse::fail("you don't have enough funds");
+    if !revoked {
+        return JsonResponse::fail("no such session");
     }
+
+    info!("Revoked session {} of {}", id, token.username);
     JsonResponse::empty_success()
 }
 
-#[post("/admin/deposit", format = "json", data = "<param>")]
-pub fn deposit(
-    server: State<state::Server>,
-    token: Token,
-    param: Json<DepositRequest>,
-) -> JsonResponse {
-    if !token.is_admin {
-        return JsonResponse::fail("you are not an admin");
-    }
+#[post("/logout/all")]
+pub fn logout_all(server: State<state::Server>, token: Token) -> JsonResponse {
+    token.require_session()?;
     let mut conn = (*server).db_conn.borrow();
-    db::deposit(&mut conn, param.0.username, param.0.amount).map_err(|e| {
-        eprintln!("Error depositing money: {}", e);
-        return JsonResponse::error("internal server error");
+    db::revoke_all_sessions(&mut *conn, &token.username).map_err(|e| {
+        error!("Error revoking sessions for {}: {}", token.username, e);
+        JsonResponse::error("internal server error")
     })?;
     JsonResponse::empty_success()
 }
 
-#[post("/admin/withdraw", format = "json", data = "<param>")]
-pub fn admin_withdraw(
+/// Changes the key of the user, logging out every other session and revoking
+/// every API key, which have to be created anew.
+#[post("/password", format = "json", data = "<param>")]
+pub fn password(
     server: State<state::Server>,
     token: Token,
-    param: Json<AdminWithdrawRequest>,
+    param: Json<PasswordRequest>,
 ) -> JsonResponse {
-    if !token.is_admin {
-        return JsonResponse::fail("you are not an admin");
-    }
+    token.require_session()?;
     let mut conn = (*server).db_conn.borrow();
-    let success = db::withdraw(&mut conn, param.0.username, param.0.amount).map_err(|e| {
-        eprintln!("Error withdrawing money: {}", e);
-        return JsonResponse::error("internal server error");
-    })?;
-    if !success {
-        return JsonResponse::fail("not enough funds");
+    let param = param.into_inner();
+
+    let valid = check_key(&mut *conn, &server, &token.username, param.old_key)?;
+    if !valid {
+        return JsonResponse::fail("invalid password");
     }
+    if param.new_key.is_empty() {
+        return JsonResponse::fail("the new password cannot be empty");
+    }
+
+    let keyhash = hash_key(&server, &param.new_key)?;
+    let revoked =
+        db::change_key(&mut *conn, &token.username, keyhash, Some(&token.jti)).map_err(|e| {
+            error!("Error changing the key of {}: {}", token.username, e);
+            JsonResponse::error("internal server error")
+        })?;
+
+    info!(
+        "Changed the key of {}, revoking {} API keys",
+        token.username, revoked
+    );
     JsonResponse::empty_success()
 }
 
-pub fn routes() -> Vec<rocket::Route> {
-    routes![
-        home,
-        info,
-        login,
-        drop,
-        register,
-        transfer,
-        withdraw,
-        history,
-        deposit,
-        admin_withdraw
-    ]
+#[post("/password/forgot", format = "json", data = "<param>")]
+pub fn forgot_password(
+    server: State<state::Server>,
+    param: Json<ForgotPasswordRequest>,
+) -> JsonResponse {
+    let mut conn = (*server).db_conn.borrow();
+    let internal = |e: redis::RedisError| {
+        error!("Error starting password reset: {}", e);
+        JsonResponse::error("internal server error")
+    };
+
+    /* The answer is the same whatever happens here, so as not to give away
+     * who does and doesn't have an account. */
+    if let Some(username) = db::canonical_username(&mut *conn, &param.username).map_err(internal)? {
+        let (email, verified) = db::email_status(&mut *conn, &username).map_err(internal)?;
+        if verified {
+            let _ = send_reset(&mut *conn, &server, &username, &email);
+        } else {
+            info!(
+                "Not mailing a password reset to {}, who has no verified email address",
+                username
+            );
+        }
+    }
+
+    JsonResponse::empty_success()
 }
 
-#[derive(Debug)]
-pub enum TokenError {
-    Missing,
-    Invalid,
+/// Sets a new key with a token mailed by `/password/forgot`, logging out every
+/// session and revoking every API key.
+#[post("/password/reset", format = "json", data = "<param>")]
+pub fn reset_password(
+    server: State<state::Server>,
+    param: Json<ResetPasswordRequest>,
+) -> JsonResponse {
+    let mut conn = (*server).db_conn.borrow();
+    let param = param.into_inner();
+    if param.new_key.is_empty() {
+        return JsonResponse::fail("the new password cannot be empty");
+    }
+
+    let username =
+        db::use_password_reset(&mut *conn, &digest_secret(&param.token)).map_err(|e| {
+            error!("Error using password reset: {}", e);
+            JsonResponse::error("internal server error")
+        })?;
+    let username = match username {
+        Some(username) => username,
+        None => return JsonResponse::fail("invalid or expired token"),
+    };
+
+    let keyhash = hash_key(&server, &param.new_key)?;
+    let revoked = db::change_key(&mut *conn, &username, keyhash, None).map_err(|e| {
+        error!("Error resetting the key of {}: {}", username, e);
+        JsonResponse::error("internal server error")
+    })?;
+    db::clear_lockout(&mut *conn, db::LockoutKind::User, &username).map_err(|e| {
+        error!("Error clearing failed logins of {}: {}", username, e);
+        JsonResponse::error("internal server error")
+    })?;
+
+    info!(
+        "Reset the key of {}, revoking {} API keys",
+        username, revoked
+    );
+    JsonResponse::empty_success()
 }
 
-fn decode_token(raw: &str, auth: &Auth) -> Option<Token> {
-    use jwt::{decode, Validation};
+#[post("/register", format = "json", data = "<param>")]
+pub fn register(
+    server: State<state::Server>,
+    client: ClientIp,
+    agent: UserAgent,
+    param: Json<RegisterRequest>,
+) -> JsonResponse {
+    let mut conn = (*server).db_conn.borrow();
+    let param = &(*param);
 
-    let validation = Validation {
-        algorithms: vec![auth.algorithm],
-        validate_exp: false,
-        ..Default::default()
+    let policy = &server.settings.names;
+    let mut fields = BTreeMap::new();
+    let username = naming::username(policy, &param.username)
+        .map_err(|what| fields.insert("username", what))
+        .ok();
+    let name = naming::display_name(policy, &param.name)
+        .map_err(|what| fields.insert("name", what))
+        .ok();
+    let email = naming::email(&param.email)
+        .map_err(|what| fields.insert("email", what))
+        .ok();
+    let (username, name, email) = match (username, name, email) {
+        (Some(username), Some(name), Some(email)) => (username, name, email),
+        _ => {
+            return JsonResponse::Failure(json!({
+                "error": "invalid registration",
+                "fields": fields
+            }))
+        }
     };
 
-    decode::<Token>(raw, auth.secret.as_bytes(), &validation)
-        .ok()
-        .map(|data| data.claims)
+    let keyhash = hash_key(&server, &param.key)?;
+
+    info!("Creating an account for {}", username);
+    let status =
+        match db::create_account(&mut *conn, username.clone(), email.clone(), name, keyhash) {
+            Ok(status) => status,
+            Err(what) => {
+                error!("Error when contacting Redis: {:?}", what);
+                return JsonResponse::fail("internal server error");
+            }
+        };
+    debug!(
+        "Account creation invoke for {} returned {:?}",
+        username, status
+    );
+
+    match status.as_str() {
+        "-KeyExists" => JsonResponse::Failure(json!({
+            "error": "user already exists",
+            "fields": { "username": "is taken" }
+        })),
+        "+OK" => {
+            /* Failing to mail them is no reason to fail the registration,
+             * they can always ask for another verification later on. */
+            let _ = send_verification(&mut *conn, &server, &username, &email);
+            JsonResponse::Success(issue_tokens(&mut *conn, &server, username, client, agent)?)
+        }
+        s @ &_ => {
+            error!("Invalid return from account creation invoke: {}", s);
+            JsonResponse::fail("internal server error")
+        }
+    }
 }
 
-impl<'a, 'r> FromRequest<'a, 'r> for Token {
-    type Error = TokenError;
+#[patch("/profile", format = "json", data = "<param>")]
+pub fn profile(
+    server: State<state::Server>,
+    token: Token,
+    param: Json<ProfileRequest>,
+) -> JsonResponse {
+    token.require_session()?;
+    let mut conn = (*server).db_conn.borrow();
+    let param = param.into_inner();
 
-    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
-        let keys: Vec<_> = request.headers().get("authorization").collect();
-        if keys.len() == 0 {
-            return Outcome::Failure((Status::Unauthorized, TokenError::Missing));
+    let policy = &server.settings.names;
+    let mut fields = BTreeMap::new();
+    let name = match param.name {
+        Some(ref name) => naming::display_name(policy, name)
+            .map_err(|what| fields.insert("name", what))
+            .ok(),
+        None => None,
+    };
+    let email = match param.email {
+        Some(ref email) => naming::email(email)
+            .map_err(|what| fields.insert("email", what))
+            .ok(),
+        None => None,
+    };
+    if !fields.is_empty() {
+        return JsonResponse::Failure(json!({
+            "error": "invalid profile",
+            "fields": fields
+        }));
+    }
+
+    /* Setting the address it already has shouldn't unverify it. */
+    let (current, _) = db::email_status(&mut *conn, &token.username).map_err(|e| {
+        error!("Error getting email address of {}: {}", token.username, e);
+        JsonResponse::error("internal server error")
+    })?;
+    let email = email.filter(|email| *email != current);
+
+    /* Whoever controls the address can reset the key, so it takes the key to
+     * change it. */
+    if email.is_some() {
+        let valid = match param.key {
+            Some(key) => check_key(&mut *conn, &server, &token.username, key)?,
+            None => false,
+        };
+        if !valid {
+            return JsonResponse::Failure(json!({
+                "error": "invalid password",
+                "fields": { "key": "is required to change the email address" }
+            }));
         }
-        let server = request
-            .guard::<State<state::Server>>()
-            .expect("Unable to obtain state for auth");
-        let auth = &server.settings.auth;
-        match decode_token(keys[0], auth) {
-            None => Outcome::Failure((Status::Unauthorized, TokenError::Invalid)),
-            Some(token) => Outcome::Success(token),
+    }
+
+    db::update_profile(
+        &mut *conn,
+        &token.username,
+        name.as_ref().map(String::as_str),
+        email.as_ref().map(String::as_str),
+    )
+    .map_err(|e| {
+        error!("Error updating the profile of {}: {}", token.username, e);
+        JsonResponse::error("internal server error")
+    })?;
+
+    let verification_sent = match email {
+        Some(ref email) => send_verification(&mut *conn, &server, &token.username, email).is_ok(),
+        None => false,
+    };
+
+    info!("Updated the profile of {}", token.username);
+    JsonResponse::Success(json!({ "verification_sent": verification_sent }))
+}
+
+#[post("/verify-email", format = "json", data = "<param>")]
+pub fn verify_email(server: State<state::Server>, param: Json<VerifyEmailRequest>) -> JsonResponse {
+    let mut conn = (*server).db_conn.borrow();
+    let verified = db::verify_email(&mut *conn, &digest_secret(&param.token)).map_err(|e| {
+        error!("Error verifying email address: {}", e);
+        JsonResponse::error("internal server error")
+    })?;
+
+    match verified {
+        Some(username) => {
+            info!("Verified the email address of {}", username);
+            JsonResponse::empty_success()
         }
+        None => JsonResponse::fail("invalid or expired token"),
+    }
+}
+
+#[post("/verify-email/resend")]
+pub fn resend_verification(server: State<state::Server>, token: Token) -> JsonResponse {
+    token.require_session()?;
+    let mut conn = (*server).db_conn.borrow();
+    let (email, verified) = db::email_status(&mut *conn, &token.username).map_err(|e| {
+        error!("Error getting email address of {}: {}", token.username, e);
+        JsonResponse::error("internal server error")
+    })?;
+    if verified {
+        return JsonResponse::fail("email address is already verified");
+    }
+    /* Accounts from before email addresses were asked for have their
+     * username in place of one. */
+    if naming::email(&email).is_err() {
+        return JsonResponse::fail("there is no email address to verify");
+    }
+
+    send_verification(&mut *conn, &server, &token.username, &email)?;
+    JsonResponse::empty_success()
+}
+
+#[post("/drop", format = "json", data = "<param>")]
+pub fn drop(server: State<state::Server>, token: Token, param: Json<DropRequest>) -> JsonResponse {
+    token.require_session()?;
+    let mut conn = (*server).db_conn.borrow();
+    let param = param.into_inner();
+
+    let valid = check_key(&mut *conn, &server, &token.username, param.key)?;
+    if !valid {
+        return JsonResponse::fail("invalid password");
+    }
+    let username = naming::fold(&token.username);
+    if param.beneficiary.as_ref().map(|name| naming::fold(name)) == Some(username) {
+        return JsonResponse::fail("you cannot hand your funds over to yourself");
+    }
+
+    info!("Dropping the account for {}", token.username);
+    let status = db::delete_account(
+        &mut *conn,
+        token.username.clone(),
+        param.beneficiary.clone(),
+    )
+    .map_err(|e| {
+        error!("Error deleting account for {}: {}", token.username, e);
+        JsonResponse::error("internal server error")
+    })?;
+
+    use db::DeleteStatus;
+    match status {
+        DeleteStatus::Success(amount) => JsonResponse::Success(json!({
+            "username": token.username,
+            "transferred": amount,
+            "beneficiary": if amount > 0 { param.beneficiary } else { None }
+        })),
+        DeleteStatus::BalanceOutstanding(amount) => JsonResponse::Failure(json!({
+            "error": "your account still has funds, name a beneficiary for them",
+            "balance": amount
+        })),
+        DeleteStatus::InvalidBeneficiary => JsonResponse::fail("invalid beneficiary user"),
+        DeleteStatus::Frozen => JsonResponse::fail("your account is frozen"),
+        DeleteStatus::NotFound => JsonResponse::fail("user does not exist"),
+    }
+}
+
+#[post("/transfer", format = "json", data = "<param>")]
+pub fn transfer(
+    server: State<state::Server>,
+    token: Token,
+    param: Json<TransferRequest>,
+) -> JsonResponse {
+    token.require_scope(|scope| scope.daily_limit().is_some())?;
+    let mut conn = (*server).db_conn.borrow();
+
+    if naming::fold(&token.username) == naming::fold(&param.0.to) {
+        return JsonResponse::fail("you cannot make transfers to yourself");
+    }
+
+    let mut spending = None;
+    if let Some(ref key) = token.api_key {
+        let limit = key.daily_limit().unwrap_or(0);
+        let day = db::spend_api_key(&mut conn, &key.id, param.0.amount, limit).map_err(|e| {
+            error!("Error counting transfer against API key {}: {}", key.id, e);
+            JsonResponse::error("internal server error")
+        })?;
+        match day {
+            Some(day) => spending = Some((&key.id, day)),
+            None => {
+                return JsonResponse::Failure(json!({
+                    "error": "transfer is over the daily limit of this API key",
+                    "limit": limit
+                }))
+            }
+        }
+    }
+
+    let r = db::transaction(&mut conn, &token.username, &param.0.to, param.0.amount);
+
+    use db::TransactionStatus;
+
+    /* Transfers that didn't go through, for whatever reason, don't count
+     * against the limit. */
+    let success = match r {
+        Ok(TransactionStatus::Success) => true,
+        _ => false,
+    };
+    if let (false, Some((id, day))) = (success, &spending) {
+        db::refund_api_key(&mut conn, id, day, param.0.amount).map_err(|e| {
+            error!("Error refunding API key {}: {}", id, e);
+            JsonResponse::error("internal server error")
+        })?;
+    }
+
+    let r = r.map_err(|e| {
+        error!("Transaction error: {}", e);
+        JsonResponse::error("internal server error")
+    })?;
+
+    match r {
+        TransactionStatus::Success => JsonResponse::empty_success(),
+        TransactionStatus::NotEnoughFunds => JsonResponse::fail("not enough funds"),
+        TransactionStatus::InvalidFrom => {
+            JsonResponse::fail("invalid source user (we're as confused as you right now)")
+        }
+        TransactionStatus::InvalidTo => JsonResponse::fail("invalid destination user"),
+        TransactionStatus::Cooldown => {
+            JsonResponse::fail("please wait before performing this action")
+        }
+        TransactionStatus::Frozen => {
+            JsonResponse::fail("an account involved in this transfer is frozen")
+        }
+    }
+}
+
+/// Lists the history of the user, newest entries first, optionally narrowed
+/// down to the entries passing every one of the filters given.
+///
+/// Pages are cut by position in the history counting from the oldest entry,
+/// which new entries don't shift around: a page only holds entries below the
+/// cursor it was asked for, and the cursor returned along with it is where the
+/// next one picks up, or null if there's nothing left. Filtered pages only look
+/// through so many entries, so they may come back short of entries but with a
+/// cursor to go on from. Totals can only be told without looking through the
+/// whole history when there are no filters, so filtered pages don't get one.
+#[get("/history?<query..>")]
+pub fn history(
+    server: State<state::Server>,
+    token: Token,
+    query: Form<HistoryQuery>,
+) -> JsonResponse {
+    let HistoryQuery {
+        cursor,
+        count,
+        counterparty,
+        direction,
+        min_amount,
+        max_amount,
+        since,
+        until,
+    } = query.into_inner();
+
+    token.require_scope(|scope| *scope == db::ApiScope::ReadOnly)?;
+    let direction = match direction.as_ref().map(String::as_str) {
+        None => None,
+        Some("in") => Some(true),
+        Some("out") => Some(false),
+        Some(_) => return JsonResponse::fail("direction must be either in or out"),
+    };
+
+    let username = naming::fold(&token.username);
+    let counterparty = counterparty.map(|name| naming::fold(&name));
+    let matches = |entry: &HistoryEntry| {
+        let incoming = entry.incoming(&username);
+        let other = match entry {
+            HistoryEntry::Transfer(transfer) if incoming => Some(&transfer.from),
+            HistoryEntry::Transfer(transfer) => Some(&transfer.to),
+            _ => None,
+        };
+
+        direction.map_or(true, |direction| direction == incoming)
+            && counterparty.as_ref().map_or(true, |name| {
+                other.map_or(false, |other| *name == naming::fold(other))
+            })
+            && min_amount.map_or(true, |min| entry.amount() >= min)
+            && max_amount.map_or(true, |max| entry.amount() <= max)
+            && since.map_or(true, |since| {
+                entry.time().map_or(false, |time| time >= since)
+            })
+            && until.map_or(true, |until| {
+                entry.time().map_or(false, |time| time <= until)
+            })
+    };
+
+    let filtered = direction.is_some()
+        || counterparty.is_some()
+        || min_amount.is_some()
+        || max_amount.is_some()
+        || since.is_some()
+        || until.is_some();
+    let count = count.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    if count == 0 {
+        return JsonResponse::fail("count must be at least 1");
+    }
+
+    let mut conn = (*server).db_conn.borrow();
+    let mut page = Vec::new();
+    let mut total = None;
+    let mut below = cursor;
+    let mut scanned = 0;
+    let next = 'scan: loop {
+        let batch = if filtered {
+            HISTORY_BATCH_SIZE
+        } else {
+            count - page.len()
+        };
+        let (len, entries) =
+            db::history(&mut conn, &token.username, below, batch).map_err(|e| {
+                error!("Error getting history: {}", e);
+                JsonResponse::error("internal server error")
+            })?;
+        if !filtered {
+            total = Some(len);
+        }
+
+        let top = below.unwrap_or(len).min(len);
+        for (offset, entry) in entries.iter().enumerate() {
+            let entry = HistoryEntry::parse(entry).map_err(|e| {
+                error!("Error deserializing history entries: {}", e);
+                JsonResponse::error("internal server error")
+            })?;
+            if !matches(&entry) {
+                continue;
+            }
+
+            page.push(entry);
+            if page.len() == count {
+                let position = top - 1 - offset;
+                break 'scan if position > 0 { Some(position) } else { None };
+            }
+        }
+
+        scanned += entries.len();
+        let bottom = top - entries.len();
+        if bottom == 0 || entries.is_empty() {
+            break None;
+        }
+        if scanned >= MAX_HISTORY_SCAN {
+            break Some(bottom);
+        }
+        below = Some(bottom);
+    };
+
+    JsonResponse::Success(json!({ "history": page, "cursor": next, "total": total }))
+}
+
+#[get("/transactions/<id>")]
+pub fn transaction(
+    server: State<state::Server>,
+    privileges: Privileges,
+    id: String,
+) -> JsonResponse {
+    let mut conn = (*server).db_conn.borrow();
+    let record = db::find_transaction(&mut conn, &id).map_err(|e| {
+        error!("Error looking up transaction {}: {}", id, e);
+        JsonResponse::error("internal server error")
+    })?;
+    let entry = match record {
+        Some(record) => HistoryEntry::parse(&record).map_err(|e| {
+            error!("Error deserializing transaction {}: {}", id, e);
+            JsonResponse::error("internal server error")
+        })?,
+        None => return JsonResponse::fail("no such transaction"),
+    };
+
+    /* Whoever isn't a party to it only gets to know it's there if they could
+     * have looked at it anyway. */
+    let username = naming::fold(&privileges.token.username);
+    if entry.involves(&username) {
+        privileges
+            .token
+            .require_scope(|scope| *scope == db::ApiScope::ReadOnly)?;
+    } else if privileges.require(Permission::ReadAccounts).is_ok() {
+        privileges.audit(&mut conn, "view_transaction", &id, None)?;
+    } else {
+        return JsonResponse::fail("no such transaction");
+    }
+
+    JsonResponse::Success(json!({ "transaction": entry }))
+}
+
+#[post("/withdraw", format = "json", data = "<param>")]
+pub fn withdraw(
+    server: State<state::Server>,
+    token: Token,
+    param: Json<WithdrawRequest>,
+) -> JsonResponse {
+    token.require_session()?;
+    let mut conn = (*server).db_conn.borrow();
+    let username = token.username.clone();
+    let status = db::withdraw(&mut conn, username, param.0.amount, &token.username, false)
+        .map_err(|e| {
+            error!("Error withdrawing for {}: {}", token.username, e);
+            JsonResponse::error("internal server error")
+        })?;
+
+    use db::TransactionStatus;
+    match status {
+        TransactionStatus::Success => JsonResponse::empty_success(),
+        TransactionStatus::Frozen => JsonResponse::fail("your account is frozen"),
+        _ => JsonResponse::fail("you don't have enough funds"),
+    }
+}
+
+#[post("/apikeys", format = "json", data = "<param>")]
+pub fn create_api_key(
+    server: State<state::Server>,
+    token: Token,
+    param: Json<ApiKeyRequest>,
+) -> JsonResponse {
+    token.require_session()?;
+    let param = param.into_inner();
+    if param.scopes.is_empty() {
+        return JsonResponse::fail("an API key needs at least one scope");
+    }
+
+    let now = Utc::now().timestamp();
+    let secret = random_string(API_KEY_SECRET_SIZE);
+    let key = db::ApiKey {
+        id: random_string(API_KEY_ID_SIZE),
+        name: param.name,
+        secret_hash: digest_secret(&secret),
+        scopes: param.scopes,
+        created: now,
+        expires: param.expires_in.map(|lifetime| now + lifetime as i64),
+    };
+
+    let mut conn = (*server).db_conn.borrow();
+    db::create_api_key(&mut conn, &token.username, &key).map_err(|e| {
+        error!("Error creating API key for {}: {}", token.username, e);
+        JsonResponse::error("internal server error")
+    })?;
+
+    info!("Created API key {} for {}", key.id, token.username);
+    JsonResponse::Success(json!({
+        "id": key.id,
+        "key": format!("{}.{}", key.id, secret),
+        "name": key.name,
+        "scopes": key.scopes,
+        "created": key.created,
+        "expires": key.expires
+    }))
+}
+
+#[get("/apikeys")]
+pub fn api_keys(server: State<state::Server>, token: Token) -> JsonResponse {
+    token.require_session()?;
+
+    let mut conn = (*server).db_conn.borrow();
+    let keys = db::list_api_keys(&mut conn, &token.username).map_err(|e| {
+        error!("Error listing API keys of {}: {}", token.username, e);
+        JsonResponse::error("internal server error")
+    })?;
+
+    let now = Utc::now().timestamp();
+    let keys = keys
+        .into_iter()
+        .map(|key| {
+            json!({
+                "id": key.id,
+                "name": key.name,
+                "scopes": key.scopes,
+                "created": key.created,
+                "expires": key.expires,
+                "expired": key.expired(now)
+            })
+        })
+        .collect::<Vec<_>>();
+    JsonResponse::Success(json!({ "keys": keys }))
+}
+
+#[delete("/apikeys/<id>")]
+pub fn revoke_api_key(server: State<state::Server>, token: Token, id: String) -> JsonResponse {
+    token.require_session()?;
+
+    let mut conn = (*server).db_conn.borrow();
+    let revoked = db::revoke_api_key(&mut conn, &token.username, &id).map_err(|e| {
+        error!("Error revoking API key of {}: {}", token.username, e);
+        JsonResponse::error("internal server error")
+    })?;
+    if !revoked {
+        return JsonResponse::fail("no such API key");
+    }
+
+    info!("Revoked API key {} of {}", id, token.username);
+    JsonResponse::empty_success()
+}
+
+#[post("/admin/deposit", format = "json", data = "<param>")]
+pub fn deposit(
+    server: State<state::Server>,
+    privileges: Privileges,
+    param: Json<DepositRequest>,
+) -> JsonResponse {
+    privileges.require(Permission::Deposit)?;
+    if let Some(limit) = privileges.deposit_limit {
+        if param.0.amount > limit {
+            return JsonResponse::Failure(json!({
+                "error": "deposit is over your limit",
+                "limit": limit
+            }));
+        }
+    }
+
+    let mut conn = (*server).db_conn.borrow();
+    let actor = &privileges.token.username;
+    db::deposit(&mut conn, param.0.username, param.0.amount, actor).map_err(|e| {
+        error!("Error depositing money as {}: {}", actor, e);
+        JsonResponse::error("internal server error")
+    })?;
+    JsonResponse::empty_success()
+}
+
+#[post("/admin/withdraw", format = "json", data = "<param>")]
+pub fn admin_withdraw(
+    server: State<state::Server>,
+    privileges: Privileges,
+    param: Json<AdminWithdrawRequest>,
+) -> JsonResponse {
+    privileges.require(Permission::Withdraw)?;
+
+    let mut conn = (*server).db_conn.borrow();
+    let actor = &privileges.token.username;
+    let status =
+        db::withdraw(&mut conn, param.0.username, param.0.amount, actor, true).map_err(|e| {
+            error!("Error withdrawing money as {}: {}", actor, e);
+            JsonResponse::error("internal server error")
+        })?;
+    match status {
+        db::TransactionStatus::Success => JsonResponse::empty_success(),
+        _ => JsonResponse::fail("not enough funds"),
+    }
+}
+
+#[get("/admin/roles/<username>")]
+pub fn roles(
+    server: State<state::Server>,
+    privileges: Privileges,
+    username: String,
+) -> JsonResponse {
+    privileges.require(Permission::ReadAccounts)?;
+
+    let mut conn = (*server).db_conn.borrow();
+    user_exists(&mut conn, &username)?;
+    let roles = db::user_roles(&mut conn, &username).map_err(|e| {
+        error!("Error getting roles of {}: {}", username, e);
+        JsonResponse::error("internal server error")
+    })?;
+    JsonResponse::Success(json!({ "roles": roles }))
+}
+
+#[post("/admin/roles/grant", format = "json", data = "<param>")]
+pub fn grant_role(
+    server: State<state::Server>,
+    privileges: Privileges,
+    param: Json<RoleRequest>,
+) -> JsonResponse {
+    privileges.require(Permission::ManageRoles)?;
+    if !server.settings.roles.contains_key(&param.role) {
+        return JsonResponse::fail("no such role");
+    }
+
+    let mut conn = (*server).db_conn.borrow();
+    user_exists(&mut conn, &param.username)?;
+    let granted = db::grant_role(&mut conn, &param.username, &param.role).map_err(|e| {
+        error!("Error granting role to {}: {}", param.username, e);
+        JsonResponse::error("internal server error")
+    })?;
+    if !granted {
+        return JsonResponse::fail("user already has this role");
+    }
+
+    info!(
+        "{} granted the {} role to {}",
+        privileges.token.username, param.role, param.username
+    );
+    privileges.audit(
+        &mut conn,
+        "grant_role",
+        &param.username,
+        Some(param.role.clone()),
+    )?;
+    JsonResponse::empty_success()
+}
+
+#[post("/admin/roles/revoke", format = "json", data = "<param>")]
+pub fn revoke_role(
+    server: State<state::Server>,
+    privileges: Privileges,
+    param: Json<RoleRequest>,
+) -> JsonResponse {
+    privileges.require(Permission::ManageRoles)?;
+
+    let mut conn = (*server).db_conn.borrow();
+    user_exists(&mut conn, &param.username)?;
+    let revoked = db::revoke_role(&mut conn, &param.username, &param.role).map_err(|e| {
+        error!("Error revoking role from {}: {}", param.username, e);
+        JsonResponse::error("internal server error")
+    })?;
+    if !revoked {
+        return JsonResponse::fail("user does not have this role");
+    }
+
+    info!(
+        "{} revoked the {} role from {}",
+        privileges.token.username, param.role, param.username
+    );
+    privileges.audit(
+        &mut conn,
+        "revoke_role",
+        &param.username,
+        Some(param.role.clone()),
+    )?;
+    JsonResponse::empty_success()
+}
+
+#[get("/admin/users?<cursor>&<count>")]
+pub fn users(
+    server: State<state::Server>,
+    privileges: Privileges,
+    cursor: Option<u64>,
+    count: Option<usize>,
+) -> JsonResponse {
+    privileges.require(Permission::ReadAccounts)?;
+
+    let mut conn = (*server).db_conn.borrow();
+    let count = count.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let (next, users) = db::list_users(&mut conn, cursor.unwrap_or(0), count).map_err(|e| {
+        error!("Error listing users: {}", e);
+        JsonResponse::error("internal server error")
+    })?;
+    privileges.audit(&mut conn, "list_users", "", None)?;
+
+    JsonResponse::Success(json!({ "users": users, "cursor": next }))
+}
+
+#[get("/admin/users/<username>")]
+pub fn user(
+    server: State<state::Server>,
+    privileges: Privileges,
+    username: String,
+) -> JsonResponse {
+    privileges.require(Permission::ReadAccounts)?;
+
+    let mut conn = (*server).db_conn.borrow();
+    user_exists(&mut conn, &username)?;
+    let info = db::user_info(&mut conn, &username).map_err(|e| {
+        error!("Error getting user info for {}: {}", username, e);
+        JsonResponse::error("internal server error")
+    })?;
+    privileges.audit(&mut conn, "view_user", &username, None)?;
+
+    JsonResponse::Success(json!({
+        "realname": info.realname,
+        "username": info.username,
+        "email": info.email,
+        "email_verified": info.email_verified,
+        "created": info.created,
+        "balance": info.balance,
+        "is_admin": info.is_admin,
+        "roles": info.roles,
+        "two_factor": info.two_factor,
+        "frozen": info.frozen
+    }))
+}
+
+#[post("/admin/users/<username>/admin")]
+pub fn grant_admin(
+    server: State<state::Server>,
+    privileges: Privileges,
+    username: String,
+) -> JsonResponse {
+    set_admin(&server, privileges, username, true)
+}
+
+#[delete("/admin/users/<username>/admin")]
+pub fn revoke_admin(
+    server: State<state::Server>,
+    privileges: Privileges,
+    username: String,
+) -> JsonResponse {
+    set_admin(&server, privileges, username, false)
+}
+
+fn set_admin(
+    server: &state::Server,
+    privileges: Privileges,
+    username: String,
+    admin: bool,
+) -> JsonResponse {
+    privileges.require(Permission::ManageRoles)?;
+    if !admin && naming::fold(&privileges.token.username) == naming::fold(&username) {
+        return JsonResponse::fail("you cannot revoke your own admin status");
+    }
+
+    let mut conn = server.db_conn.borrow();
+    user_exists(&mut conn, &username)?;
+    let changed = db::set_admin(&mut conn, &username, admin).map_err(|e| {
+        error!("Error changing admin status of {}: {}", username, e);
+        JsonResponse::error("internal server error")
+    })?;
+    if !changed {
+        return JsonResponse::fail(if admin {
+            "user is already an admin"
+        } else {
+            "user is not an admin"
+        });
+    }
+
+    info!(
+        "{} {} admin status {} {}",
+        privileges.token.username,
+        if admin { "granted" } else { "revoked" },
+        if admin { "to" } else { "from" },
+        username
+    );
+    let action = if admin { "grant_admin" } else { "revoke_admin" };
+    privileges.audit(&mut conn, action, &username, None)?;
+    JsonResponse::empty_success()
+}
+
+#[post("/admin/freeze", format = "json", data = "<param>")]
+pub fn freeze(
+    server: State<state::Server>,
+    privileges: Privileges,
+    param: Json<FreezeRequest>,
+) -> JsonResponse {
+    privileges.require(Permission::Freeze)?;
+    let FreezeRequest { username, reason } = param.into_inner();
+    if reason.trim().is_empty() {
+        return JsonResponse::fail("a reason is needed to freeze an account");
+    }
+
+    let mut conn = (*server).db_conn.borrow();
+    user_exists(&mut conn, &username)?;
+    let freeze = db::Freeze {
+        reason,
+        actor: privileges.token.username.clone(),
+        time: Utc::now().timestamp(),
+    };
+    let frozen = db::freeze(&mut conn, &username, &freeze).map_err(|e| {
+        error!("Error freezing {}: {}", username, e);
+        JsonResponse::error("internal server error")
+    })?;
+    if !frozen {
+        return JsonResponse::fail("account is already frozen");
+    }
+
+    warn!(
+        "{} froze the account of {}: {}",
+        freeze.actor, username, freeze.reason
+    );
+    privileges.audit(&mut conn, "freeze", &username, Some(freeze.reason))?;
+    JsonResponse::empty_success()
+}
+
+#[post("/admin/unfreeze", format = "json", data = "<param>")]
+pub fn unfreeze(
+    server: State<state::Server>,
+    privileges: Privileges,
+    param: Json<UnfreezeRequest>,
+) -> JsonResponse {
+    privileges.require(Permission::Freeze)?;
+
+    let mut conn = (*server).db_conn.borrow();
+    user_exists(&mut conn, &param.username)?;
+    let unfrozen = db::unfreeze(&mut conn, &param.username).map_err(|e| {
+        error!("Error unfreezing {}: {}", param.username, e);
+        JsonResponse::error("internal server error")
+    })?;
+    if !unfrozen {
+        return JsonResponse::fail("account is not frozen");
+    }
+
+    info!(
+        "{} unfroze the account of {}",
+        privileges.token.username, param.username
+    );
+    privileges.audit(&mut conn, "unfreeze", &param.username, None)?;
+    JsonResponse::empty_success()
+}
+
+#[get("/admin/lockouts?<cursor>&<count>")]
+pub fn lockouts(
+    server: State<state::Server>,
+    privileges: Privileges,
+    cursor: Option<u64>,
+    count: Option<usize>,
+) -> JsonResponse {
+    privileges.require(Permission::ReadAccounts)?;
+
+    let mut conn = (*server).db_conn.borrow();
+    let count = count.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let (next, lockouts) =
+        db::list_lockouts(&mut conn, cursor.unwrap_or(0), count).map_err(|e| {
+            error!("Error listing lockouts: {}", e);
+            JsonResponse::error("internal server error")
+        })?;
+    privileges.audit(&mut conn, "list_lockouts", "", None)?;
+
+    JsonResponse::Success(json!({ "lockouts": lockouts, "cursor": next }))
+}
+
+#[delete("/admin/lockouts/<kind>/<subject>")]
+pub fn clear_lockout(
+    server: State<state::Server>,
+    privileges: Privileges,
+    kind: String,
+    subject: String,
+) -> JsonResponse {
+    privileges.require(Permission::ManageLockouts)?;
+    let kind = match db::LockoutKind::from_name(&kind) {
+        Some(kind) => kind,
+        None => return JsonResponse::fail("no such kind of lockout"),
+    };
+
+    let mut conn = (*server).db_conn.borrow();
+    let cleared = db::clear_lockout(&mut conn, kind, &subject).map_err(|e| {
+        error!("Error clearing lockout of {}: {}", subject, e);
+        JsonResponse::error("internal server error")
+    })?;
+    if !cleared {
+        return JsonResponse::fail("there are no failed logins to clear");
+    }
+
+    info!(
+        "{} cleared the failed logins of {} {}",
+        privileges.token.username,
+        kind.name(),
+        subject
+    );
+    privileges.audit(
+        &mut conn,
+        "clear_lockout",
+        &subject,
+        Some(kind.name().to_owned()),
+    )?;
+    JsonResponse::empty_success()
+}
+
+#[get("/admin/audit?<start>&<count>")]
+pub fn audit(
+    server: State<state::Server>,
+    privileges: Privileges,
+    start: Option<usize>,
+    count: Option<usize>,
+) -> JsonResponse {
+    privileges.require(Permission::ReadAccounts)?;
+
+    let mut conn = (*server).db_conn.borrow();
+    let count = count.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let entries = db::audit_log(&mut conn, start.unwrap_or(0), count).map_err(|e| {
+        error!("Error reading the audit log: {}", e);
+        JsonResponse::error("internal server error")
+    })?;
+    JsonResponse::Success(json!({ "entries": entries }))
+}
+
+/// Replays the ledger and checks every balance against it.
+#[get("/admin/ledger")]
+pub fn ledger(server: State<state::Server>, privileges: Privileges) -> JsonResponse {
+    privileges.require(Permission::ReadAccounts)?;
+
+    let mut conn = (*server).db_conn.borrow();
+    let (ledger, mismatches) = db::verify_ledger(&mut conn).map_err(|e| {
+        error!("Error verifying the ledger: {}", e);
+        JsonResponse::error("internal server error")
+    })?;
+    privileges.audit(&mut conn, "verify_ledger", "", None)?;
+
+    let balance = |account: &str| ledger.balances.get(account).cloned().unwrap_or(0);
+    JsonResponse::Success(json!({
+        "entries": ledger.entries,
+        "minted": -balance(db::MINT_ACCOUNT),
+        "burnt": balance(db::BURN_ACCOUNT),
+        "unbalanced": ledger.unbalanced,
+        "mismatches": mismatches,
+    }))
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![
+        home,
+        jwks,
+        info,
+        login,
+        login_two_factor,
+        totp_enroll,
+        totp_confirm,
+        totp_disable,
+        refresh,
+        logout,
+        logout_all,
+        sessions,
+        revoke_session,
+        password,
+        forgot_password,
+        reset_password,
+        drop,
+        register,
+        profile,
+        verify_email,
+        resend_verification,
+        transfer,
+        withdraw,
+        history,
+        transaction,
+        create_api_key,
+        api_keys,
+        revoke_api_key,
+        deposit,
+        admin_withdraw,
+        roles,
+        grant_role,
+        revoke_role,
+        users,
+        user,
+        grant_admin,
+        revoke_admin,
+        freeze,
+        unfreeze,
+        lockouts,
+        clear_lockout,
+        audit,
+        ledger
+    ]
+}
+
+#[derive(Debug)]
+pub enum TokenError {
+    Missing,
+    Invalid,
+    Revoked,
+    Database,
+}
+
+/// Checks the key of a user. Keyhashes that can't be verified against are
+/// answered for just like a wrong key would be, having been logged already.
+fn check_key(
+    conn: &mut redis::Connection,
+    server: &state::Server,
+    username: &str,
+    key: String,
+) -> Result<bool, JsonValue> {
+    match db::validate(conn, username.to_owned(), key, &*server.hasher) {
+        Ok(valid) => Ok(valid),
+        Err(db::ValidateError::Corrupted(_)) => Ok(false),
+        Err(db::ValidateError::Redis(e)) => {
+            error!("Error validating credentials of {}: {}", username, e);
+            Err(JsonResponse::error("internal server error"))
+        }
+    }
+}
+
+/// Checks a second factor of the user, spending it in the process so that it
+/// can't be used again.
+fn check_second_factor(
+    conn: &mut redis::Connection,
+    username: &str,
+    factor: &SecondFactor,
+) -> Result<bool, JsonValue> {
+    let internal = |e: redis::RedisError| {
+        error!("Error checking second factor of {}: {}", username, e);
+        JsonResponse::error("internal server error")
+    };
+
+    if let Some(ref code) = factor.code {
+        let secret = match db::totp_state(conn, username).map_err(internal)? {
+            Some(ref state) if state.enabled => state.secret.clone(),
+            _ => return Ok(false),
+        };
+        match totp::verify(&secret, code, Utc::now().timestamp() as u64) {
+            Some(step) => db::use_totp_step(conn, username, step).map_err(internal),
+            None => Ok(false),
+        }
+    } else if let Some(ref code) = factor.recovery_code {
+        let hash = totp::hash_recovery_code(code);
+        let used = db::use_recovery_code(conn, username, &hash).map_err(internal)?;
+        if used {
+            info!("{} used a recovery code", username);
+        }
+        Ok(used)
+    } else {
+        Ok(false)
+    }
+}
+
+/// Refuses the login if any of the subjects are locked out.
+fn check_lockouts(
+    conn: &mut redis::Connection,
+    subjects: &[(db::LockoutKind, String)],
+) -> Result<(), JsonValue> {
+    let now = Utc::now().timestamp();
+    for (kind, subject) in subjects {
+        let lockout = db::lockout(conn, *kind, subject).map_err(|e| {
+            error!("Error checking lockout of {}: {}", subject, e);
+            JsonResponse::error("internal server error")
+        })?;
+        if lockout.locked_until > now {
+            return Err(json!({
+                "error": "too many failed logins, try again later",
+                "locked_out": true,
+                "retry_after": lockout.locked_until - now
+            }));
+        }
+    }
+    Ok(())
+}
+
+fn count_failed_login(
+    conn: &mut redis::Connection,
+    policy: &settings::Lockout,
+    subjects: &[(db::LockoutKind, String)],
+) -> Result<(), JsonValue> {
+    for (kind, subject) in subjects {
+        let locked_until = db::fail_login(conn, *kind, subject, policy).map_err(|e| {
+            error!("Error counting failed login of {}: {}", subject, e);
+            JsonResponse::error("internal server error")
+        })?;
+        if locked_until > 0 {
+            warn!(
+                "Locking logins of {} {} out until {}",
+                kind.name(),
+                subject,
+                locked_until
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Forgets the failed logins of the user, once they're fully logged in.
+fn forget_failed_logins(conn: &mut redis::Connection, username: &str) -> Result<(), JsonValue> {
+    db::clear_lockout(conn, db::LockoutKind::User, username).map_err(|e| {
+        error!("Error clearing failed logins of {}: {}", username, e);
+        JsonResponse::error("internal server error")
+    })?;
+    Ok(())
+}
+
+fn hash_key(server: &state::Server, key: &str) -> Result<String, JsonValue> {
+    server.hasher.generate(key).map_err(|what| match what {
+        keyhash::GenerateError::KeyTooLong => JsonResponse::error("password is too long"),
+        keyhash::GenerateError::Failed(what) => {
+            error!("Error generating keyhash: {}", what);
+            JsonResponse::error("internal server error")
+        }
+    })
+}
+
+fn user_exists(conn: &mut redis::Connection, username: &str) -> Result<(), JsonValue> {
+    let userhash = db::find_userhash(conn, username).map_err(|e| {
+        error!("Error looking up user {}: {}", username, e);
+        JsonResponse::error("internal server error")
+    })?;
+    match userhash {
+        Some(_) => Ok(()),
+        None => Err(JsonResponse::error("user does not exist")),
+    }
+}
+
+/// Digest API key secrets are stored as. They're random enough for a plain
+/// digest to do, unlike keys.
+fn digest_secret(secret: &str) -> String {
+    use ring::digest;
+
+    digest::digest(&digest::SHA256, secret.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|val| format!("{:02x}", val))
+        .collect()
+}
+
+/// Mails the user a token to verify their email address with.
+fn send_verification(
+    conn: &mut redis::Connection,
+    server: &state::Server,
+    username: &str,
+    email: &str,
+) -> Result<(), JsonValue> {
+    let token = random_string(SESSION_ID_SIZE);
+    let lifetime = server.settings.auth.verification_lifetime;
+    db::create_email_verification(conn, &digest_secret(&token), username, email, lifetime)
+        .map_err(|e| {
+            error!("Error creating email verification for {}: {}", username, e);
+            JsonResponse::error("internal server error")
+        })?;
+
+    let link = token_link(&server.settings.mail.verification_url, token);
+    let message = mail::Message {
+        to: email.to_owned(),
+        subject: "Verify your email address".to_owned(),
+        body: format!(
+            "Hello, {}!\n\n\
+             To verify that this email address is yours, use the following:\n\n\
+             \x20   {}\n\n\
+             It is valid for the next {} hours. If you never asked for this,\n\
+             there is nothing you need to do.\n",
+            username,
+            link,
+            lifetime / 3600
+        ),
+    };
+    server.mailer.send(&message).map_err(|e| {
+        error!("Error mailing verification to {}: {}", username, e);
+        JsonResponse::error("could not send mail")
+    })
+}
+
+/// Mails the user a token to reset their key with.
+fn send_reset(
+    conn: &mut redis::Connection,
+    server: &state::Server,
+    username: &str,
+    email: &str,
+) -> Result<(), JsonValue> {
+    let token = random_string(SESSION_ID_SIZE);
+    let lifetime = server.settings.auth.reset_lifetime;
+    db::create_password_reset(conn, &digest_secret(&token), username, lifetime).map_err(|e| {
+        error!("Error creating password reset for {}: {}", username, e);
+        JsonResponse::error("internal server error")
+    })?;
+
+    let link = token_link(&server.settings.mail.reset_url, token);
+    let message = mail::Message {
+        to: email.to_owned(),
+        subject: "Reset your password".to_owned(),
+        body: format!(
+            "Hello, {}!\n\n\
+             To choose a new password, use the following:\n\n\
+             \x20   {}\n\n\
+             It is valid for the next {} minutes, and only once. If you never\n\
+             asked for this, there is nothing you need to do.\n",
+            username,
+            link,
+            lifetime / 60
+        ),
+    };
+    server.mailer.send(&message).map_err(|e| {
+        error!("Error mailing password reset to {}: {}", username, e);
+        JsonResponse::error("could not send mail")
+    })
+}
+
+/// The link a mailed token is to be followed through, or the bare token if
+/// there's no page to link to.
+fn token_link(url: &Option<String>, token: String) -> String {
+    match url {
+        Some(url) => format!("{}{}", url, token),
+        None => token,
+    }
+}
+
+fn random_string(len: usize) -> String {
+    use rand::distributions::Alphanumeric;
+    use rand::{thread_rng, Rng};
+
+    thread_rng().sample_iter(&Alphanumeric).take(len).collect()
+}
+
+fn encode_claims<T: Serialize>(claims: &T, keys: &signing::Keys) -> Result<String, JsonValue> {
+    keys.sign(claims)
+        .map_err(|_| JsonResponse::error("failed to sign token"))
+}
+
+/// Signs a fresh access token along with the refresh token for the given
+/// state of a session.
+fn sign_tokens(
+    server: &state::Server,
+    username: String,
+    session: String,
+    state: db::Session,
+    now: i64,
+) -> Result<JsonValue, JsonValue> {
+    let auth = &server.settings.auth;
+    let access = encode_claims(
+        &Token {
+            username: username.clone(),
+            jti: session.clone(),
+            aud: ACCESS_AUDIENCE.to_owned(),
+            iat: now,
+            exp: now + auth.access_lifetime as i64,
+            api_key: None,
+        },
+        &server.keys,
+    )?;
+    let refresh = encode_claims(
+        &RefreshToken {
+            username,
+            jti: session,
+            nonce: state.nonce,
+            aud: REFRESH_AUDIENCE.to_owned(),
+            iat: now,
+            exp: state.expires,
+        },
+        &server.keys,
+    )?;
+
+    Ok(json!({
+        "token": access,
+        "refresh_token": refresh,
+        "expires_in": auth.access_lifetime
+    }))
+}
+
+/// Opens a new session for the user and hands out its first pair of tokens.
+fn issue_tokens(
+    conn: &mut redis::Connection,
+    server: &state::Server,
+    username: String,
+    client: ClientIp,
+    agent: UserAgent,
+) -> Result<JsonValue, JsonValue> {
+    let auth = &server.settings.auth;
+    let now = Utc::now().timestamp();
+    let id = random_string(SESSION_ID_SIZE);
+    let session = db::Session {
+        nonce: random_string(SESSION_ID_SIZE),
+        expires: now + auth.refresh_lifetime as i64,
+        created: now,
+        last_used: now,
+        ip: client.0.map(|ip| ip.to_string()),
+        user_agent: agent.0,
+    };
+
+    db::create_session(conn, &username, &id, &session).map_err(|e| {
+        error!("Error creating session for {}: {}", username, e);
+        JsonResponse::error("internal server error")
+    })?;
+    sign_tokens(server, username, id, session, now)
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Token {
+    type Error = TokenError;
+
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        let server = request
+            .guard::<State<state::Server>>()
+            .expect("Unable to obtain state for auth");
+        let keys: Vec<_> = request.headers().get("authorization").collect();
+        if keys.len() == 0 {
+            return match request.headers().get_one(API_KEY_HEADER) {
+                Some(raw) => Token::from_api_key(&server, raw),
+                None => Outcome::Failure((Status::Unauthorized, TokenError::Missing)),
+            };
+        }
+        let token = match server.keys.verify::<Token>(keys[0], ACCESS_AUDIENCE) {
+            None => return Outcome::Failure((Status::Unauthorized, TokenError::Invalid)),
+            Some(token) => token,
+        };
+
+        let mut conn = server.db_conn.borrow();
+        match db::touch_session(&mut *conn, &token.username, &token.jti) {
+            Ok(true) => Outcome::Success(token),
+            Ok(false) => Outcome::Failure((Status::Unauthorized, TokenError::Revoked)),
+            Err(e) => {
+                error!("Error checking session of {}: {}", token.username, e);
+                Outcome::Failure((Status::InternalServerError, TokenError::Database))
+            }
+        }
+    }
+}
+
+impl Token {
+    /// Stands a token in for the API key, given as "<id>.<secret>".
+    fn from_api_key(server: &state::Server, raw: &str) -> Outcome<Self, TokenError> {
+        let mut parts = raw.splitn(2, '.');
+        let (id, secret) = match (parts.next(), parts.next()) {
+            (Some(id), Some(secret)) => (id, secret),
+            _ => return Outcome::Failure((Status::Unauthorized, TokenError::Invalid)),
+        };
+
+        let mut conn = server.db_conn.borrow();
+        let (username, key) = match db::find_api_key(&mut *conn, id) {
+            Ok(Some(found)) => found,
+            Ok(None) => return Outcome::Failure((Status::Unauthorized, TokenError::Invalid)),
+            Err(e) => {
+                error!("Error looking up API key {}: {}", id, e);
+                return Outcome::Failure((Status::InternalServerError, TokenError::Database));
+            }
+        };
+        /* The digests are compared in constant time so the time a guess
+         * takes to be turned down says nothing about the stored one. */
+        let given = digest_secret(secret);
+        if constant_time::verify_slices_are_equal(key.secret_hash.as_bytes(), given.as_bytes())
+            .is_err()
+        {
+            return Outcome::Failure((Status::Unauthorized, TokenError::Invalid));
+        }
+        if key.expired(Utc::now().timestamp()) {
+            return Outcome::Failure((Status::Unauthorized, TokenError::Revoked));
+        }
+
+        Outcome::Success(Token {
+            username,
+            jti: key.id.clone(),
+            aud: API_KEY_AUDIENCE.to_owned(),
+            iat: key.created,
+            exp: key.expires.unwrap_or(i64::max_value()),
+            api_key: Some(key),
+        })
+    }
+}
+
+/// Address the request came from, taken from the X-Real-IP header when we're
+/// set up to trust it.
+pub struct ClientIp(Option<IpAddr>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientIp {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        let server = request
+            .guard::<State<state::Server>>()
+            .expect("Unable to obtain state for client address");
+
+        Outcome::Success(ClientIp(if server.settings.trust_real_ip {
+            request.client_ip()
+        } else {
+            request.remote().map(|addr| addr.ip())
+        }))
+    }
+}
+
+/// User agent the request was made with, cut short if it's overly long.
+pub struct UserAgent(Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for UserAgent {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(UserAgent(
+            request
+                .headers()
+                .get_one("User-Agent")
+                .map(|agent| agent.chars().take(MAX_USER_AGENT_SIZE).collect()),
+        ))
+    }
+}
+
+/// Everything the bearer of a token may do, looked up at the time of the
+/// request rather than trusted from the token, so that revoking a role takes
+/// effect on the very next request.
+pub struct Privileges {
+    token: Token,
+    permissions: BTreeSet<Permission>,
+    /// Largest single deposit allowed, if deposits are limited at all.
+    deposit_limit: Option<Balance>,
+    /// Whether privileges were withheld for want of two-factor authentication.
+    needs_two_factor: bool,
+}
+impl Privileges {
+    fn resolve(
+        conn: &mut redis::Connection,
+        settings: &Settings,
+        token: Token,
+    ) -> redis::RedisResult<Privileges> {
+        let (mut permissions, deposit_limit) = Self::granted(conn, &settings.roles, &token)?;
+
+        /* API keys only carry over the permissions they were scoped for. */
+        if let Some(ref key) = token.api_key {
+            let deposit = key.scopes.contains(&db::ApiScope::AdminDeposit);
+            permissions = permissions
+                .into_iter()
+                .filter(|permission| deposit && *permission == Permission::Deposit)
+                .collect();
+        }
+
+        if settings.two_factor.enforce_for_admins && !permissions.is_empty() {
+            let enabled = db::totp_state(conn, &token.username)?
+                .map(|state| state.enabled)
+                .unwrap_or(false);
+            if !enabled {
+                return Ok(Privileges {
+                    token,
+                    permissions: BTreeSet::new(),
+                    deposit_limit: None,
+                    needs_two_factor: true,
+                });
+            }
+        }
+
+        Ok(Privileges {
+            token,
+            permissions,
+            deposit_limit,
+            needs_two_factor: false,
+        })
+    }
+
+    /// Permissions granted to the user and the deposit limit they come with.
+    fn granted(
+        conn: &mut redis::Connection,
+        roles: &BTreeMap<String, Role>,
+        token: &Token,
+    ) -> redis::RedisResult<(BTreeSet<Permission>, Option<Balance>)> {
+        /* Admins have always been allowed to do anything. */
+        if db::is_admin(conn, token.username.clone())? {
+            return Ok((Permission::all(), None));
+        }
+
+        let mut permissions = BTreeSet::new();
+        let mut deposit_limit = None;
+        let mut limited = true;
+        for name in db::user_roles(conn, &token.username)? {
+            let role = match roles.get(&name) {
+                Some(role) => role,
+                None => {
+                    warn!("{} holds the unknown role {}", token.username, name);
+                    continue;
+                }
+            };
+
+            if role.permissions.contains(&Permission::Deposit) {
+                match role.deposit_limit {
+                    Some(limit) => deposit_limit = deposit_limit.max(Some(limit)),
+                    None => limited = false,
+                }
+            }
+            permissions.extend(role.permissions.iter().cloned());
+        }
+
+        Ok((permissions, if limited { deposit_limit } else { None }))
+    }
+
+    /// Records something done with these privileges in the audit log.
+    fn audit(
+        &self,
+        conn: &mut redis::Connection,
+        action: &str,
+        target: &str,
+        detail: Option<String>,
+    ) -> Result<(), JsonValue> {
+        db::audit(conn, &self.token.username, action, target, detail).map_err(|e| {
+            error!("Error writing to the audit log: {}", e);
+            JsonResponse::error("internal server error")
+        })
+    }
+
+    fn require(&self, permission: Permission) -> Result<(), JsonValue> {
+        if self.permissions.contains(&permission) {
+            Ok(())
+        } else if self.needs_two_factor {
+            Err(JsonResponse::error(
+                "enable two-factor authentication to use your privileges",
+            ))
+        } else {
+            Err(JsonResponse::error("you are not allowed to do this"))
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Privileges {
+    type Error = TokenError;
+
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        let token = request.guard::<Token>()?;
+        let server = request
+            .guard::<State<state::Server>>()
+            .expect("Unable to obtain state for auth");
+
+        let mut conn = server.db_conn.borrow();
+        match Privileges::resolve(&mut *conn, &server.settings, token) {
+            Ok(privileges) => Outcome::Success(privileges),
+            Err(e) => {
+                error!("Error resolving privileges: {}", e);
+                Outcome::Failure((Status::InternalServerError, TokenError::Database))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::{account, connect, username};
+    use crate::{keyhash, pool};
+    use rocket::local::Client;
+
+    /// Server over the test database, with a lockout policy slow enough that
+    /// tests don't outrun it.
+    fn client() -> Client {
+        let mut settings = Settings::default();
+        settings.key_hash.algorithm = settings::HashAlgorithm::BCrypt;
+        settings.key_hash.cost = 4;
+        settings.lockout.base_delay = 60;
+        settings.mail.outbox_directory =
+            std::env::temp_dir().join(format!("joao-outbox-{:016x}", rand::random::<u64>()));
+
+        let server = state::Server {
+            db_conn: pool::Pool::generate(1, |_| connect()),
+            hasher: keyhash::hasher(&settings.key_hash),
+            keys: signing::Keys::load(&settings.auth)
+                .unwrap_or_else(|_| panic!("Could not make up keys"))
+                .0,
+            mailer: mail::mailer(&settings.mail),
+            settings,
+        };
+
+        use rocket::config::{Config, Environment, LoggingLevel};
+        let config = Config::build(Environment::Development)
+            .log_level(LoggingLevel::Off)
+            .finalize()
+            .unwrap();
+        Client::new(rocket::custom(config).manage(server).mount("/", routes())).unwrap()
+    }
+
+    fn post(client: &Client, path: &str, body: JsonValue) -> serde_json::Value {
+        let mut response = client
+            .post(path)
+            .header(ContentType::JSON)
+            .body(body.0.to_string())
+            .dispatch();
+        serde_json::from_str(&response.body_string().unwrap()).unwrap()
+    }
+
+    #[test]
+    #[ignore]
+    fn failed_second_factors_lock_the_account() {
+        let mut conn = connect();
+        let username = username("twofactor");
+        account(&mut conn, &username, "hunter2");
+        db::set_pending_totp(&mut conn, &username, &totp::generate_secret()).unwrap();
+        db::enable_totp(&mut conn, &username, 0, &[]).unwrap();
+
+        /* Every guess gets a challenge of its own, so only the lockout can
+         * stop them. */
+        let client = client();
+        let login = json!({ "username": username, "key": "hunter2" });
+        let free_attempts = Settings::default().lockout.free_attempts;
+        for _ in 0..=free_attempts {
+            let reply = post(&client, "/login", login.clone());
+            assert_eq!(reply["two_factor"], true, "{}", reply);
+            let guess = json!({ "challenge": reply["challenge"], "recovery_code": "GUESSED1" });
+            let reply = post(&client, "/login/2fa", guess);
+            assert_eq!(reply["error"], "invalid code", "{}", reply);
+        }
+
+        let reply = post(&client, "/login", login);
+        assert_eq!(reply["success"], false);
+        assert_eq!(reply["locked_out"], true, "{}", reply);
     }
 }
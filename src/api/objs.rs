@@ -1,5 +1,6 @@
 //! Objects related to requests and responses performed by the API.
-use super::{Balance, Token, Transfer};
+use super::{Balance, Transfer};
+use crate::naming;
 use rocket_contrib::json::JsonValue;
 use serde_derive::{Deserialize, Serialize};
 
@@ -10,11 +11,74 @@ pub struct LoginRequest {
     pub key: String,
 }
 
+/* Two-factor authentication */
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChallengeRequest {
+    pub challenge: String,
+    #[serde(flatten)]
+    pub factor: SecondFactor,
+}
+
+/// Either a code from the authenticator or one of the recovery codes.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SecondFactor {
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TotpConfirmRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TotpDisableRequest {
+    pub key: String,
+    #[serde(flatten)]
+    pub factor: SecondFactor,
+}
+
+/* Refresh */
+#[derive(Debug, Clone, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+/* Password */
+#[derive(Debug, Clone, Deserialize)]
+pub struct PasswordRequest {
+    pub old_key: String,
+    pub new_key: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ForgotPasswordRequest {
+    pub username: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub new_key: String,
+}
+
+/* API keys */
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKeyRequest {
+    /// What the key is for, so that it can be told apart from the others.
+    pub name: String,
+    pub scopes: Vec<crate::db::ApiScope>,
+    /// Number of seconds the key is valid for, if it ever expires at all.
+    pub expires_in: Option<u64>,
+}
+
 /* Drop */
 #[derive(Debug, Clone, Deserialize)]
 pub struct DropRequest {
-    username: String,
-    token: Token,
+    /// The account's key, confirming the deletion.
+    pub key: String,
+    /// Who gets whatever is left of the balance, if anything is left at all.
+    pub beneficiary: Option<String>,
 }
 
 /* Transfer */
@@ -30,10 +94,110 @@ pub struct WithdrawRequest {
 }
 
 #[derive(Debug, Deserialize, Serialize)]
-pub struct HistoryEntry {
+pub struct TransferEntry {
     pub from: String,
     pub to: String,
     pub amount: u32,
+    /// Id the transaction can be looked up by. Transactions made before they
+    /// were given ids have none.
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Unix timestamp of the transaction, by the database's clock.
+    #[serde(default)]
+    pub time: Option<i64>,
+}
+
+/// Money put into or taken out of an account from outside of the bank.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BalanceEntry {
+    /// User whose account it was.
+    pub account: String,
+    /// User who put the money in or took it out, be it the owner or an admin.
+    pub actor: String,
+    pub amount: u32,
+    /// Balance of the account right after.
+    pub balance: i64,
+    pub id: String,
+    pub time: i64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum HistoryEntry {
+    Transfer(TransferEntry),
+    Deposit(BalanceEntry),
+    Withdrawal(BalanceEntry),
+}
+impl HistoryEntry {
+    /// Parses an entry as found in the database. Those from before entries
+    /// were told apart by their kind are all transfers.
+    pub fn parse(entry: &str) -> serde_json::Result<HistoryEntry> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Stored {
+            Typed(HistoryEntry),
+            Untyped(TransferEntry),
+        }
+
+        Ok(match serde_json::from_str(entry)? {
+            Stored::Typed(entry) => entry,
+            Stored::Untyped(transfer) => HistoryEntry::Transfer(transfer),
+        })
+    }
+
+    pub fn amount(&self) -> u32 {
+        match self {
+            HistoryEntry::Transfer(transfer) => transfer.amount,
+            HistoryEntry::Deposit(entry) | HistoryEntry::Withdrawal(entry) => entry.amount,
+        }
+    }
+
+    pub fn time(&self) -> Option<i64> {
+        match self {
+            HistoryEntry::Transfer(transfer) => transfer.time,
+            HistoryEntry::Deposit(entry) | HistoryEntry::Withdrawal(entry) => Some(entry.time),
+        }
+    }
+
+    /// Whether the entry is of money coming into the account of the user,
+    /// whose username is given folded.
+    pub fn incoming(&self, username: &str) -> bool {
+        match self {
+            HistoryEntry::Transfer(transfer) => naming::fold(&transfer.to) == username,
+            HistoryEntry::Deposit(_) => true,
+            HistoryEntry::Withdrawal(_) => false,
+        }
+    }
+
+    /// Whether the user, whose username is given folded, is a party to the
+    /// entry.
+    pub fn involves(&self, username: &str) -> bool {
+        match self {
+            HistoryEntry::Transfer(transfer) => {
+                naming::fold(&transfer.from) == username || naming::fold(&transfer.to) == username
+            }
+            HistoryEntry::Deposit(entry) | HistoryEntry::Withdrawal(entry) => {
+                naming::fold(&entry.account) == username
+            }
+        }
+    }
+}
+
+/// Query of a page of history, along with the filters narrowing it down.
+#[derive(Debug, FromForm)]
+pub struct HistoryQuery {
+    pub cursor: Option<usize>,
+    pub count: Option<usize>,
+    /// Only entries to or from this user.
+    pub counterparty: Option<String>,
+    /// Only entries coming into the account (`in`) or out of it (`out`).
+    pub direction: Option<String>,
+    pub min_amount: Option<u32>,
+    pub max_amount: Option<u32>,
+    /// Only entries made at or after this Unix timestamp.
+    pub since: Option<i64>,
+    /// Only entries made at or before this Unix timestamp.
+    pub until: Option<i64>,
 }
 
 /* Deposit */
@@ -49,10 +213,44 @@ pub struct AdminWithdrawRequest {
     pub amount: u32,
 }
 
+/* Freezes */
+#[derive(Debug, Clone, Deserialize)]
+pub struct FreezeRequest {
+    pub username: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UnfreezeRequest {
+    pub username: String,
+}
+
+/* Roles */
+#[derive(Debug, Clone, Deserialize)]
+pub struct RoleRequest {
+    pub username: String,
+    pub role: String,
+}
+
 /* Registration */
 #[derive(Debug, Clone, Deserialize)]
 pub struct RegisterRequest {
     pub username: String,
     pub name: String,
+    pub email: String,
     pub key: String,
 }
+
+/* Profile */
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProfileRequest {
+    pub name: Option<String>,
+    pub email: Option<String>,
+    /// The current key, which changing the email address takes.
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct VerifyEmailRequest {
+    pub token: String,
+}
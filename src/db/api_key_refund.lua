@@ -0,0 +1,17 @@
+--[[
+    api_key_refund.lua: Takes back what was counted against the daily limit of
+    an API key for a transfer that didn't go through.
+
+    KEYS[1]: api key spent on the day the transfer was counted
+    ARGV[1]: amount to take back
+
+    Counters that are gone by now are left alone, rather than being brought
+    back without an expiry.
+]]
+
+if redis.call("exists", KEYS[1]) == 0 then
+    return 0
+end
+
+redis.call("decrby", KEYS[1], ARGV[1])
+return 1
@@ -0,0 +1,19 @@
+--[[
+    api_key_spend.lua: Counts a transfer against the daily limit of an API key.
+
+    KEYS[1]: api key spent today
+    ARGV[1]: amount to transfer
+    ARGV[2]: daily limit
+    ARGV[3]: lifetime of the counter, in seconds
+
+    Returns 0 if the amount fit under the limit and was counted, 1 otherwise.
+]]
+
+local spent = tonumber(redis.call("get", KEYS[1]) or "0")
+if spent + tonumber(ARGV[1]) > tonumber(ARGV[2]) then
+    return 1
+end
+
+redis.call("incrby", KEYS[1], ARGV[1])
+redis.call("expire", KEYS[1], ARGV[3])
+return 0
@@ -0,0 +1,33 @@
+--[[
+    change_key.lua: Replaces the key of a user, logging out their sessions and
+    revoking their API keys, any of which could be in the wrong hands by now.
+
+    KEYS[1]: user keyhash
+    KEYS[2]: user salt
+    KEYS[3]: user tokens
+    KEYS[4]: user api keys
+    KEYS[5]: api key table
+    ARGV[1]: new keyhash
+    ARGV[2]: new salt
+    ARGV[3]: id of the session to be kept, if any
+
+    Returns the number of API keys revoked.
+]]
+
+redis.call("set", KEYS[1], ARGV[1])
+redis.call("set", KEYS[2], ARGV[2])
+
+local sessions = redis.call("hkeys", KEYS[3])
+for _, id in ipairs(sessions) do
+    if id ~= ARGV[3] then
+        redis.call("hdel", KEYS[3], id)
+    end
+end
+
+local api_keys = redis.call("hkeys", KEYS[4])
+for _, id in ipairs(api_keys) do
+    redis.call("hdel", KEYS[5], id)
+end
+redis.call("del", KEYS[4])
+
+return #api_keys
@@ -0,0 +1,460 @@
+//! Consistency checks over the whole database, for the `check` command.
+//!
+//! Everything is looked for by walking the keyspace, so these are meant to be
+//! run every once in a while by an operator, not on every request. Only the
+//! findings that can be fixed without guessing at what the data should have
+//! been are ever repaired, and only after making sure nothing moved under us
+//! since they were found.
+use super::{names, MINT_ACCOUNT};
+use crate::naming;
+use redis::{Commands, PipelineCommands};
+use serde_derive::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+/// Number of keys asked for on every `SCAN` call.
+const SCAN_BATCH_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Finding {
+    /// Keys of an account no username in the uid table leads to.
+    OrphanedKeys {
+        userhash: String,
+        keys: Vec<String>,
+        balance: Option<String>,
+    },
+    /// A username in the uid table leading to an account that has neither a
+    /// username nor a balance.
+    DanglingUid { username: String, userhash: String },
+    /// An account with a username, but no balance.
+    MissingBalance { username: String, userhash: String },
+    /// A balance that is either negative or not a number at all.
+    InvalidBalance {
+        username: String,
+        userhash: String,
+        balance: String,
+    },
+    /// A balance that isn't what the history of the account adds up to, if
+    /// it adds up to anything at all.
+    HistoryMismatch {
+        username: String,
+        userhash: String,
+        balance: i64,
+        history: Option<i64>,
+    },
+    /// A ledger entry whose postings don't add up to nothing.
+    UnbalancedEntry { id: String },
+    /// A balance that isn't what the ledger adds up to.
+    LedgerMismatch {
+        account: String,
+        username: Option<String>,
+        recorded: Option<i64>,
+        computed: i64,
+        /// Whether the account shows up in the ledger at all.
+        journaled: bool,
+        /// Last entry of the ledger when it was read.
+        #[serde(skip)]
+        last_entry: Option<String>,
+    },
+}
+impl Finding {
+    /// Whether this is one of the findings `repair()` knows how to fix.
+    pub fn repairable(&self) -> bool {
+        match self {
+            Finding::OrphanedKeys { balance, .. } => {
+                balance.as_ref().map_or(true, |balance| balance == "0")
+            }
+            Finding::DanglingUid { .. } => true,
+            Finding::LedgerMismatch {
+                recorded: Some(recorded),
+                journaled: false,
+                ..
+            } => *recorded > 0,
+            _ => false,
+        }
+    }
+}
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Finding::OrphanedKeys { userhash, keys, .. } => write!(
+                f,
+                "{} keys of account {} can't be reached from any username",
+                keys.len(),
+                userhash
+            ),
+            Finding::DanglingUid { username, userhash } => write!(
+                f,
+                "username {} leads to account {}, which doesn't exist",
+                username, userhash
+            ),
+            Finding::MissingBalance { username, userhash } => {
+                write!(f, "account {} of {} has no balance", userhash, username)
+            }
+            Finding::InvalidBalance {
+                username,
+                userhash,
+                balance,
+            } => write!(
+                f,
+                "account {} of {} has an invalid balance of {:?}",
+                userhash, username, balance
+            ),
+            Finding::HistoryMismatch {
+                username,
+                balance,
+                history,
+                ..
+            } => match history {
+                Some(history) => write!(
+                    f,
+                    "history of {} adds up to a balance of {}, but it is {}",
+                    username, history, balance
+                ),
+                None => write!(
+                    f,
+                    "history of {} doesn't account for its balance of {}",
+                    username, balance
+                ),
+            },
+            Finding::UnbalancedEntry { id } => {
+                write!(f, "ledger entry {} doesn't balance", id)
+            }
+            Finding::LedgerMismatch {
+                account,
+                username,
+                recorded,
+                computed,
+                ..
+            } => {
+                write!(f, "ledger has account {}", account)?;
+                if let Some(username) = username {
+                    write!(f, " of {}", username)?;
+                }
+                write!(f, " at {}, but its balance is ", computed)?;
+                match recorded {
+                    Some(recorded) => write!(f, "{}", recorded),
+                    None => write!(f, "gone"),
+                }
+            }
+        }
+    }
+}
+
+/// Goes through every key matching the pattern.
+fn scan(conn: &mut redis::Connection, pattern: &str) -> redis::RedisResult<Vec<String>> {
+    let mut keys = Vec::new();
+    let mut cursor = 0u64;
+    loop {
+        let (next, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(pattern)
+            .arg("COUNT")
+            .arg(SCAN_BATCH_SIZE)
+            .query(conn)?;
+        keys.extend(batch);
+
+        if next == 0 {
+            return Ok(keys);
+        }
+        cursor = next;
+    }
+}
+
+/// Works out the balance the history of an account explains, if it does.
+/// Deposits and withdrawals carry the balance they left the account with, so
+/// only entries newer than the latest of them count. Histories without any
+/// of them, as those of accounts from before the starting balance went into
+/// the history, don't explain anything.
+fn history_balance(username: &str, history: &[String]) -> Option<i64> {
+    let username = naming::fold(username);
+    let mut net = 0i64;
+    for entry in history {
+        let entry: serde_json::Value = serde_json::from_str(entry).ok()?;
+        let amount = entry.get("amount")?.as_i64()?;
+        match entry.get("kind").and_then(|kind| kind.as_str()) {
+            None | Some("transfer") => {
+                let party = |field: &str| {
+                    entry
+                        .get(field)
+                        .and_then(|name| name.as_str())
+                        .map(naming::fold)
+                };
+                if party("to").as_ref() == Some(&username) {
+                    net += amount;
+                } else if party("from").as_ref() == Some(&username) {
+                    net -= amount;
+                }
+            }
+            Some("deposit") | Some("withdrawal") => {
+                return Some(entry.get("balance")?.as_i64()? + net);
+            }
+            Some(_) => return None,
+        }
+    }
+    None
+}
+
+/// Looks through the whole database for anything out of place.
+pub fn check(conn: &mut redis::Connection) -> redis::RedisResult<Vec<Finding>> {
+    let mut findings = Vec::new();
+
+    /* The keys have to be gone through before the uid table is read, lest
+     * accounts made in between pass for orphans. */
+    let mut accounts: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for key in scan(conn, &names::user_keys("*"))? {
+        let userhash = match key.splitn(3, ':').nth(1) {
+            Some(userhash) => userhash.to_owned(),
+            None => continue,
+        };
+        accounts.entry(userhash).or_insert_with(Vec::new).push(key);
+    }
+    let uids: HashMap<String, String> = conn.hgetall(names::uid_table())?;
+    let filed = uids.values().cloned().collect::<BTreeSet<_>>();
+
+    for (userhash, keys) in accounts {
+        if !filed.contains(&userhash) {
+            let balance = conn.get(names::user_balance(&userhash))?;
+            findings.push(Finding::OrphanedKeys {
+                userhash,
+                keys,
+                balance,
+            });
+        }
+    }
+
+    let mut uids = uids.into_iter().collect::<Vec<_>>();
+    uids.sort();
+    for (username, userhash) in uids {
+        /* Accounts made after the keys were gone through aren't in there, so
+         * whether they exist is asked for again. */
+        let name: Option<String> = conn.get(names::user_username(&userhash))?;
+        let exists = name.is_some();
+        let username = name.unwrap_or(username);
+        let balance: Option<String> = conn.get(names::user_balance(&userhash))?;
+        let balance = match balance {
+            Some(balance) => balance,
+            None if exists => {
+                findings.push(Finding::MissingBalance { username, userhash });
+                continue;
+            }
+            None => {
+                findings.push(Finding::DanglingUid { username, userhash });
+                continue;
+            }
+        };
+        let balance = match balance.parse::<i64>() {
+            Ok(value) if value >= 0 => value,
+            _ => {
+                findings.push(Finding::InvalidBalance {
+                    username,
+                    userhash,
+                    balance,
+                });
+                continue;
+            }
+        };
+
+        let history: Vec<String> = conn.lrange(names::user_history(&userhash), 0, -1)?;
+        match history_balance(&username, &history) {
+            Some(history) if history == balance => {}
+            history => findings.push(Finding::HistoryMismatch {
+                username,
+                userhash,
+                balance,
+                history,
+            }),
+        }
+    }
+
+    let (ledger, mismatches) = super::verify_ledger(conn)?;
+    for id in &ledger.unbalanced {
+        findings.push(Finding::UnbalancedEntry { id: id.clone() });
+    }
+    for mismatch in mismatches {
+        findings.push(Finding::LedgerMismatch {
+            journaled: ledger.balances.contains_key(&mismatch.account),
+            account: mismatch.account,
+            username: mismatch.username,
+            recorded: mismatch.recorded,
+            computed: mismatch.computed,
+            last_entry: ledger.last.clone(),
+        });
+    }
+
+    Ok(findings)
+}
+
+/// Fixes what was found, if it can be fixed safely and is still the way it was
+/// found. Returns whether anything was done.
+pub fn repair(conn: &mut redis::Connection, finding: &Finding) -> redis::RedisResult<bool> {
+    if !finding.repairable() {
+        return Ok(false);
+    }
+
+    match finding {
+        /* Nothing would be lost with them but keys no one can get to, and the
+         * API keys they hold, which go from the global table along with them. */
+        Finding::OrphanedKeys { userhash, keys, .. } => {
+            let balance_key = names::user_balance(userhash);
+            let api_keys = names::user_api_keys(userhash);
+            redis::transaction(conn, &[&balance_key, &api_keys], |conn, pipe| {
+                let balance: Option<String> = conn.get(&balance_key)?;
+                if balance.map_or(false, |balance| balance != "0") {
+                    return Ok(Some(false));
+                }
+                let ids: Vec<String> = conn.hkeys(&api_keys)?;
+                pipe.del(&keys[..]).del(&api_keys);
+                if !ids.is_empty() {
+                    pipe.hdel(names::api_key_table(), &ids[..]);
+                }
+                let done: Option<redis::Value> = pipe.query(conn)?;
+                Ok(done.map(|_| true))
+            })
+        }
+        Finding::DanglingUid { username, userhash } => {
+            let (table, username_key) = (names::uid_table(), names::user_username(userhash));
+            let balance_key = names::user_balance(userhash);
+            redis::transaction(
+                conn,
+                &[&table, &username_key, &balance_key],
+                |conn, pipe| {
+                    let current: Option<String> = conn.hget(&table, username)?;
+                    let exists: bool = conn.exists(&username_key)?;
+                    let funded: bool = conn.exists(&balance_key)?;
+                    if current.as_ref() != Some(userhash) || exists || funded {
+                        return Ok(Some(false));
+                    }
+                    let done: Option<redis::Value> = pipe.hdel(&table, username).query(conn)?;
+                    Ok(done.map(|_| true))
+                },
+            )
+        }
+        /* Accounts from before the ledger get their balance journaled as the
+         * one they opened with, so long as nothing was journaled since. */
+        Finding::LedgerMismatch {
+            account,
+            recorded: Some(recorded),
+            last_entry,
+            ..
+        } => {
+            let (ledger, balance_key) = (names::ledger(), names::user_balance(account));
+            redis::transaction(conn, &[&ledger, &balance_key], |conn, pipe| {
+                let last: Vec<(String, Vec<String>)> = redis::cmd("XREVRANGE")
+                    .arg(&ledger)
+                    .arg("+")
+                    .arg("-")
+                    .arg("COUNT")
+                    .arg(1)
+                    .query(conn)?;
+                let balance: Option<i64> = conn.get(&balance_key)?;
+                if last.first().map(|(id, _)| id) != last_entry.as_ref()
+                    || balance != Some(*recorded)
+                {
+                    return Ok(Some(false));
+                }
+
+                let postings = serde_json::to_string(&[
+                    super::Posting {
+                        account: MINT_ACCOUNT.to_owned(),
+                        amount: -recorded,
+                    },
+                    super::Posting {
+                        account: account.clone(),
+                        amount: *recorded,
+                    },
+                ])
+                .expect("Postings can always be serialized");
+                let done: Option<redis::Value> = pipe
+                    .cmd("XADD")
+                    .arg(&ledger)
+                    .arg("*")
+                    .arg("kind")
+                    .arg("open")
+                    .arg("postings")
+                    .arg(postings)
+                    .query(conn)?;
+                Ok(done.map(|_| true))
+            })
+        }
+        _ => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn transfer(from: &str, to: &str, amount: i64) -> String {
+        json!({ "kind": "transfer", "from": from, "to": to, "amount": amount }).to_string()
+    }
+
+    fn deposit(amount: i64, balance: i64) -> String {
+        json!({ "kind": "deposit", "amount": amount, "balance": balance }).to_string()
+    }
+
+    fn withdrawal(amount: i64, balance: i64) -> String {
+        json!({ "kind": "withdrawal", "amount": amount, "balance": balance }).to_string()
+    }
+
+    #[test]
+    fn opening_deposit_is_the_balance() {
+        let history = vec![deposit(100, 100)];
+        assert_eq!(history_balance("alice", &history), Some(100));
+    }
+
+    #[test]
+    fn transfers_count_from_the_latest_deposit() {
+        /* Newest first, as the history is pushed to. */
+        let history = vec![
+            transfer("bob", "alice", 30),
+            transfer("alice", "bob", 50),
+            deposit(100, 100),
+        ];
+        assert_eq!(history_balance("alice", &history), Some(80));
+    }
+
+    #[test]
+    fn entries_older_than_a_withdrawal_dont_count() {
+        let history = vec![
+            transfer("alice", "bob", 5),
+            withdrawal(20, 60),
+            transfer("bob", "alice", 1000),
+            deposit(100, 100),
+        ];
+        assert_eq!(history_balance("alice", &history), Some(55));
+    }
+
+    #[test]
+    fn names_are_compared_folded() {
+        let history = vec![transfer("Bob", "ALICE", 10), deposit(100, 100)];
+        assert_eq!(history_balance("Alice", &history), Some(110));
+    }
+
+    #[test]
+    fn transfers_without_a_kind_still_count() {
+        let legacy = json!({ "from": "alice", "to": "bob", "amount": 40 }).to_string();
+        let history = vec![legacy, deposit(100, 100)];
+        assert_eq!(history_balance("alice", &history), Some(60));
+    }
+
+    #[test]
+    fn history_without_deposits_explains_nothing() {
+        assert_eq!(history_balance("alice", &[]), None);
+        let history = vec![transfer("bob", "alice", 10)];
+        assert_eq!(history_balance("alice", &history), None);
+    }
+
+    #[test]
+    fn unknown_or_malformed_entries_explain_nothing() {
+        let unknown = json!({ "kind": "interest", "amount": 1 }).to_string();
+        assert_eq!(history_balance("alice", &[unknown, deposit(1, 1)]), None);
+        let garbled = "{\"kind\": \"transfer\"".to_owned();
+        assert_eq!(history_balance("alice", &[garbled, deposit(1, 1)]), None);
+        let unbalanced = json!({ "kind": "deposit", "amount": 1 }).to_string();
+        assert_eq!(history_balance("alice", &[unbalanced]), None);
+    }
+}
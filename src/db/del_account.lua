@@ -1,4 +1,5 @@
--- del_account.lua: Deletes a new user at a key.
+-- del_account.lua: Deletes a user at a key, handing whatever is left of its
+-- balance over to a beneficiary.
 -- Parameters:
 --      KEYS[1]  - user:email
 --      KEYS[2]  - user:name
@@ -10,14 +11,91 @@
 --      KEYS[8]  - user:balance
 --      KEYS[9]  - user:username
 --      KEYS[10] - uid_table
+--      KEYS[11] - user:admin
+--      KEYS[12] - user:roles
+--      KEYS[13] - user:totp
+--      KEYS[14] - user:recovery
+--      KEYS[15] - user:apikeys
+--      KEYS[16] - api_key_table
+--      KEYS[17] - user:email_verified
+--      KEYS[18] - user:reset
+--      KEYS[19] - user:created
+--      KEYS[20] - user:frozen
+--      KEYS[21] - transaction counter
+--      KEYS[22] - transaction table
+--      KEYS[23] - ledger
+--      KEYS[24] - beneficiary:balance (optional)
+--      KEYS[25] - beneficiary:history (optional)
+--      KEYS[26] - beneficiary:frozen (optional)
+--
+--      ARGV[1]  - Userhash.
+--      ARGV[2]  - Username, folded as it's filed in the uid table.
+--      ARGV[3]  - Beneficiary's username (optional).
+--      ARGV[4]  - Beneficiary's userhash (optional).
+--
+-- Returns a pair of the status and the amount that was (or would have been)
+-- handed over to the beneficiary.
 --
 
-if not redis.call("get", KEYS[8]) then
-	return "-KeyDoesNotExist"
+-- The hand over is stamped with the server's clock, which means only the
+-- writes we make can be replicated.
+redis.replicate_commands()
+
+local balance = redis.call("get", KEYS[8])
+if not balance then
+	return {"-KeyDoesNotExist", 0}
+end
+
+local amount = tonumber(balance)
+if redis.call("exists", KEYS[20]) == 1 then
+	return {"-Frozen", amount}
+end
+if amount > 0 then
+	if not KEYS[24] then
+		return {"-BalanceOutstanding", amount}
+	end
+	if    ARGV[4] == ARGV[1]
+	   or not redis.call("get", KEYS[24])
+	   or redis.call("exists", KEYS[26]) == 1 then
+		return {"-InvalidBeneficiary", amount}
+	end
 end
 
 local username = redis.call("get", KEYS[9])
-redis.call("hdel", KEYS[10], username)
+
+if amount > 0 then
+	redis.call("incrby", KEYS[24], balance)
+
+	-- Record the hand over in the beneficiary's history.
+	local record = {}
+	record.kind    = "transfer"
+	record.from    = username
+	record.to      = ARGV[3]
+	record.amount  = amount
+	record.id      = tostring(redis.call("incr", KEYS[21]))
+	record.time    = tonumber(redis.call("time")[1])
+	local json_record = cjson.encode(record)
+
+	redis.call("hset", KEYS[22], record.id, json_record)
+	redis.call("lpush", KEYS[25], json_record)
+
+	local postings = {
+		{account = ARGV[1], amount = -amount},
+		{account = ARGV[4], amount = amount},
+	}
+	redis.call("xadd", KEYS[23], "*",
+		"kind", "transfer",
+		"transaction", record.id,
+		"postings", cjson.encode(postings))
+end
+
+-- Accounts from before usernames were folded are filed as they were given,
+-- so only let go of whichever entry is actually ours.
+for _, uid in ipairs({ARGV[2], username or ARGV[2]}) do
+	if redis.call("hget", KEYS[10], uid) == ARGV[1] then
+		redis.call("hdel", KEYS[10], uid)
+	end
+end
 
 redis.call("del", KEYS[9])
 redis.call("del", KEYS[8])
@@ -28,6 +106,17 @@ redis.call("del", KEYS[4])
 redis.call("del", KEYS[5])
 redis.call("del", KEYS[6])
 redis.call("del", KEYS[7])
+redis.call("del", KEYS[11])
+redis.call("del", KEYS[12])
+redis.call("del", KEYS[13])
+redis.call("del", KEYS[14])
+redis.call("del", KEYS[17])
+redis.call("del", KEYS[18])
+redis.call("del", KEYS[19])
 
-return "+OK"
+for _, id in ipairs(redis.call("hkeys", KEYS[15])) do
+	redis.call("hdel", KEYS[16], id)
+end
+redis.call("del", KEYS[15])
 
+return {"+OK", amount}
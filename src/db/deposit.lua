@@ -0,0 +1,45 @@
+--[[
+    deposit.lua: Puts money into an account, recording it in its history.
+
+    KEYS[1]: user balance
+    KEYS[2]: user history
+    KEYS[3]: transaction counter
+    KEYS[4]: transaction table
+    KEYS[5]: ledger
+    ARGV[1]: amount to deposit
+    ARGV[2]: username
+    ARGV[3]: who made the deposit
+    ARGV[4]: userhash
+    ARGV[5]: account the money is minted from
+
+    Returns the resulting balance.
+]]
+
+-- We read the clock further down, so only the writes may get replicated.
+redis.replicate_commands()
+
+local balance = redis.call("incrby", KEYS[1], ARGV[1])
+
+local record = {}
+record.kind    = "deposit"
+record.account = ARGV[2]
+record.actor   = ARGV[3]
+record.amount  = tonumber(ARGV[1])
+record.balance = balance
+record.id      = tostring(redis.call("incr", KEYS[3]))
+record.time    = tonumber(redis.call("time")[1])
+local json_record = cjson.encode(record)
+
+redis.call("hset", KEYS[4], record.id, json_record)
+redis.call("lpush", KEYS[2], json_record)
+
+local postings = {
+    {account = ARGV[5], amount = -record.amount},
+    {account = ARGV[4], amount = record.amount},
+}
+redis.call("xadd", KEYS[5], "*",
+    "kind", "deposit",
+    "transaction", record.id,
+    "postings", cjson.encode(postings))
+
+return balance
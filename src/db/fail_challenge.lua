@@ -0,0 +1,14 @@
+--[[
+    fail_challenge.lua: Counts a failed attempt at a login challenge.
+
+    KEYS[1]: login challenge
+
+    Returns how many attempts have failed, or nothing if the challenge expired
+    in the meantime, rather than bringing it back without an expiry.
+]]
+
+if redis.call("exists", KEYS[1]) == 0 then
+    return false
+end
+
+return redis.call("hincrby", KEYS[1], "attempts", 1)
@@ -0,0 +1,27 @@
+--[[
+    history_page.lua: Reads a page of the history of a user, newest first.
+
+    KEYS[1]: user history
+    ARGV[1]: position the page ends right below, counting from the oldest
+             entry, or nothing for the newest entries
+    ARGV[2]: most entries to read
+
+    Returns the length of the whole history along with the entries read.
+    Positions past the newest entry read the newest entries, rather than
+    nothing at all.
+]]
+
+local len = redis.call("llen", KEYS[1])
+local below = len
+if ARGV[1] ~= "" then
+    below = math.min(tonumber(ARGV[1]), len)
+end
+
+local count = tonumber(ARGV[2])
+if below == 0 or count == 0 then
+    return {len, {}}
+end
+
+-- New entries go in at the head, so positions count from the tail.
+local start = len - below
+return {len, redis.call("lrange", KEYS[1], start, start + count - 1)}
@@ -0,0 +1,27 @@
+--[[
+    login_failure.lua: Counts a failed login, locking logins out for longer
+    and longer the more failures pile up.
+
+    KEYS[1]: lockout
+    ARGV[1]: current time
+    ARGV[2]: failures allowed before locking out
+    ARGV[3]: delay after the first failure past those, in seconds
+    ARGV[4]: longest delay, in seconds
+    ARGV[5]: how long failures are remembered for, in seconds
+
+    Returns the time logins are locked out until, 0 if they aren't.
+]]
+
+local now = tonumber(ARGV[1])
+local failures = redis.call("hincrby", KEYS[1], "failures", 1)
+local over = failures - tonumber(ARGV[2])
+
+local locked_until = 0
+if over > 0 then
+    local delay = math.min(tonumber(ARGV[3]) * 2 ^ (over - 1), tonumber(ARGV[4]))
+    locked_until = now + delay
+    redis.call("hset", KEYS[1], "locked_until", locked_until)
+end
+
+redis.call("expire", KEYS[1], math.max(tonumber(ARGV[5]), locked_until - now))
+return locked_until
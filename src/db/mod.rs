@@ -2,16 +2,72 @@ pub const TRANSACTION_SCRIPT: &'static str = include_str!("transaction.lua");
 pub const NEW_ACCOUNT_SCRIPT: &'static str = include_str!("new_account.lua");
 pub const DEL_ACCOUNT_SCRIPT: &'static str = include_str!("del_account.lua");
 pub const WITHDRAW_SCRIPT: &'static str = include_str!("withdraw.lua");
+pub const DEPOSIT_SCRIPT: &'static str = include_str!("deposit.lua");
+pub const REFRESH_SCRIPT: &'static str = include_str!("refresh_session.lua");
+pub const TOUCH_SESSION_SCRIPT: &'static str = include_str!("touch_session.lua");
+pub const CHANGE_KEY_SCRIPT: &'static str = include_str!("change_key.lua");
+pub const TOTP_STEP_SCRIPT: &'static str = include_str!("use_totp_step.lua");
+pub const API_KEY_SPEND_SCRIPT: &'static str = include_str!("api_key_spend.lua");
+pub const API_KEY_REFUND_SCRIPT: &'static str = include_str!("api_key_refund.lua");
+pub const LOGIN_FAILURE_SCRIPT: &'static str = include_str!("login_failure.lua");
+pub const FAIL_CHALLENGE_SCRIPT: &'static str = include_str!("fail_challenge.lua");
+pub const HISTORY_PAGE_SCRIPT: &'static str = include_str!("history_page.lua");
+pub const VERIFY_EMAIL_SCRIPT: &'static str = include_str!("verify_email.lua");
+pub const PASSWORD_RESET_SCRIPT: &'static str = include_str!("use_password_reset.lua");
 
 pub const INITIAL_BALANCE: u32 = 500;
 pub const MAX_RETRIES: usize = 256;
 pub const USERHASH_SIZE: usize = 32;
+/// Keyhashes carry their own salt, so this is all that goes in `user:salt`.
+pub const NO_SALT: &'static str = "";
+/// How long the daily spending of an API key is kept around, in seconds.
+pub const API_KEY_SPENT_LIFETIME: u64 = 2 * 24 * 60 * 60;
+/// Ledger account money put into the bank comes out of.
+pub const MINT_ACCOUNT: &'static str = "mint";
+/// Ledger account money taken out of the bank goes into.
+pub const BURN_ACCOUNT: &'static str = "burn";
+/// Who the bank itself does things as, such as depositing starting balances.
+pub const SYSTEM_ACTOR: &'static str = "system";
+/// Number of ledger entries read at a time.
+const LEDGER_BATCH_SIZE: usize = 1000;
+
+pub mod check;
 
 mod names {
     pub fn uid_table() -> String {
         "uids".to_owned()
     }
 
+    /// Set once every username in the uid table is filed folded.
+    pub fn uid_table_folded() -> String {
+        "uids:folded".to_owned()
+    }
+
+    pub fn audit_log() -> String {
+        "audit".to_owned()
+    }
+
+    pub fn api_key_table() -> String {
+        "apikeys".to_owned()
+    }
+
+    pub fn transaction_table() -> String {
+        "transactions".to_owned()
+    }
+
+    pub fn transaction_counter() -> String {
+        "transactions:last".to_owned()
+    }
+
+    pub fn ledger() -> String {
+        "ledger".to_owned()
+    }
+
+    /// Pattern matching every key of the user.
+    pub fn user_keys(userhash: &str) -> String {
+        format!("user:{}:*", userhash)
+    }
+
     pub fn user_name(userhash: &str) -> String {
         format!("user:{}:name", userhash)
     }
@@ -24,6 +80,18 @@ mod names {
         format!("user:{}:email", userhash)
     }
 
+    pub fn user_frozen(userhash: &str) -> String {
+        format!("user:{}:frozen", userhash)
+    }
+
+    pub fn user_created(userhash: &str) -> String {
+        format!("user:{}:created", userhash)
+    }
+
+    pub fn user_email_verified(userhash: &str) -> String {
+        format!("user:{}:email_verified", userhash)
+    }
+
     pub fn user_cooldown(userhash: &str) -> String {
         format!("user:{}:cd_lock", userhash)
     }
@@ -51,22 +119,161 @@ mod names {
     pub fn user_admin(userhash: &str) -> String {
         format!("user:{}:admin", userhash)
     }
+
+    pub fn user_roles(userhash: &str) -> String {
+        format!("user:{}:roles", userhash)
+    }
+
+    pub fn user_totp(userhash: &str) -> String {
+        format!("user:{}:totp", userhash)
+    }
+
+    pub fn user_recovery(userhash: &str) -> String {
+        format!("user:{}:recovery", userhash)
+    }
+
+    pub fn user_api_keys(userhash: &str) -> String {
+        format!("user:{}:apikeys", userhash)
+    }
+
+    pub fn api_key_spent(id: &str, day: &str) -> String {
+        format!("apikey:{}:spent:{}", id, day)
+    }
+
+    pub fn lockout(kind: &str, subject: &str) -> String {
+        format!("lockout:{}:{}", kind, subject)
+    }
+
+    pub fn login_challenge(id: &str) -> String {
+        format!("challenge:{}", id)
+    }
+
+    pub fn email_verification(digest: &str) -> String {
+        format!("verify:{}", digest)
+    }
+
+    pub fn password_reset(digest: &str) -> String {
+        format!("reset:{}", digest)
+    }
+
+    pub fn user_reset(userhash: &str) -> String {
+        format!("user:{}:reset", userhash)
+    }
 }
 
 pub fn get_userhash(
     connection: &mut redis::Connection,
     username: &str,
 ) -> redis::RedisResult<String> {
+    find_userhash(connection, username)?
+        .ok_or_else(|| (redis::ErrorKind::TypeError, "No such user").into())
+}
+
+/// Same as `get_userhash()`, but yields `None` for users that don't exist
+/// instead of failing.
+pub fn find_userhash(
+    connection: &mut redis::Connection,
+    username: &str,
+) -> redis::RedisResult<Option<String>> {
     use redis::Commands;
-    connection.hget(names::uid_table(), username)
+    let folded = naming::fold(username);
+    if folded == username {
+        return connection.hget(names::uid_table(), username);
+    }
+
+    /* Accounts from before usernames were folded are filed as given. */
+    let userhash: Option<String> = connection.hget(names::uid_table(), username)?;
+    match userhash {
+        None => connection.hget(names::uid_table(), &folded),
+        userhash => Ok(userhash),
+    }
+}
+
+/// Files every username in the uid table under its folded spelling, as they
+/// have been filed since usernames were folded, so that registering another
+/// spelling of an old username is caught like that of any other. This only
+/// ever has to happen once. Returns how many usernames were filed anew, or,
+/// without touching anything, the sets of usernames of different accounts
+/// that fold alike, which have to be sorted out by hand.
+pub fn fold_uid_table(
+    conn: &mut redis::Connection,
+) -> redis::RedisResult<Result<usize, Vec<Vec<String>>>> {
+    use redis::{Commands, PipelineCommands};
+    use std::collections::{BTreeSet, HashMap};
+    let (table, folded_key) = (names::uid_table(), names::uid_table_folded());
+    let mut outcome = Ok(0);
+    redis::transaction(conn, &[&table, &folded_key], |conn, pipe| {
+        if conn.exists(&folded_key)? {
+            return Ok(Some(()));
+        }
+
+        let uids: HashMap<String, String> = conn.hgetall(&table)?;
+        let mut spellings: BTreeMap<String, BTreeMap<&String, &String>> = BTreeMap::new();
+        for (uid, userhash) in &uids {
+            spellings
+                .entry(naming::fold(uid))
+                .or_insert_with(BTreeMap::new)
+                .insert(uid, userhash);
+        }
+
+        let mut clashes = Vec::new();
+        let mut refiled = 0;
+        for (folded, uids) in &spellings {
+            let userhashes = uids.values().collect::<BTreeSet<_>>();
+            if userhashes.len() > 1 {
+                clashes.push(uids.keys().map(|uid| (*uid).clone()).collect());
+                continue;
+            }
+            let userhash = userhashes
+                .into_iter()
+                .next()
+                .expect("Spellings are never empty");
+            for uid in uids.keys().filter(|uid| **uid != folded) {
+                pipe.hset(&table, folded, *userhash).hdel(&table, *uid);
+                refiled += 1;
+            }
+        }
+        if !clashes.is_empty() {
+            outcome = Err(clashes);
+            return Ok(Some(()));
+        }
+
+        outcome = Ok(refiled);
+        pipe.set(&folded_key, 1).query(conn)
+    })?;
+    Ok(outcome)
+}
+
+/// The username of a user as it was registered, whichever way it's spelled
+/// in the one given.
+pub fn canonical_username(
+    connection: &mut redis::Connection,
+    username: &str,
+) -> redis::RedisResult<Option<String>> {
+    let userhash = match find_userhash(connection, username)? {
+        Some(userhash) => userhash,
+        None => return Ok(None),
+    };
+
+    use redis::Commands;
+    connection.get(names::user_username(&userhash))
 }
 
 #[derive(Debug)]
 pub struct UserInfo {
     pub realname: String,
     pub username: String,
+    /// Accounts from before email addresses were asked for have none.
+    pub email: Option<String>,
+    pub email_verified: bool,
+    /// Unix timestamp of the registration, unknown for accounts from before
+    /// it was recorded.
+    pub created: Option<i64>,
     pub balance: u32,
     pub is_admin: bool,
+    pub roles: Vec<String>,
+    pub two_factor: bool,
+    pub frozen: Option<Freeze>,
 }
 
 pub fn user_info(conn: &mut redis::Connection, username: &str) -> redis::RedisResult<UserInfo> {
@@ -76,21 +283,96 @@ pub fn user_info(conn: &mut redis::Connection, username: &str) -> redis::RedisRe
 
     use redis::Commands;
 
+    /* Which is where those accounts got a copy of their username instead. */
+    let email: String = conn.get(names::user_email(&userhash))?;
+    let email = Some(email).filter(|email| naming::email(email).is_ok());
+
     Ok(UserInfo {
         realname: conn.get(names::user_name(&userhash))?,
-        username: username.to_owned(),
+        username: conn.get(names::user_username(&userhash))?,
+        email,
+        email_verified: conn.exists(names::user_email_verified(&userhash))?,
+        created: conn.get(names::user_created(&userhash))?,
         balance: conn.get(names::user_balance(&userhash))?,
         is_admin: is_admin(conn, username.to_owned())?,
+        roles: conn.smembers(names::user_roles(&userhash))?,
+        two_factor: totp_state(conn, username)?
+            .map(|state| state.enabled)
+            .unwrap_or(false),
+        frozen: freeze_state(conn, username)?,
     })
 }
 
-pub fn history(conn: &mut redis::Connection, username: &str) -> redis::RedisResult<Vec<String>> {
+#[derive(Debug, Clone, Serialize)]
+pub struct UserSummary {
+    pub username: String,
+    pub balance: Balance,
+    pub is_admin: bool,
+}
+
+/// Lists a page of users, starting at the given `HSCAN` cursor. Returns the
+/// cursor to the next page along with the page, the cursor being zero once
+/// every user has been listed. Users are listed under their username as they
+/// registered it.
+pub fn list_users(
+    conn: &mut redis::Connection,
+    cursor: u64,
+    count: usize,
+) -> redis::RedisResult<(u64, Vec<UserSummary>)> {
+    let (next, page): (u64, Vec<String>) = redis::cmd("HSCAN")
+        .arg(names::uid_table())
+        .arg(cursor)
+        .arg("COUNT")
+        .arg(count)
+        .query(conn)?;
+
+    use redis::Commands;
+    let mut users = Vec::with_capacity(page.len() / 2);
+    for pair in page.chunks(2) {
+        let (uid, userhash) = (&pair[0], &pair[1]);
+        let username: Option<String> = conn.get(names::user_username(userhash))?;
+        let username = username.unwrap_or_else(|| uid.clone());
+
+        /* Accounts from before usernames were folded may be filed under both
+         * spellings, which would have them listed twice. The folded entry is
+         * the one that counts, unless it leads somewhere else. */
+        let folded = naming::fold(&username);
+        if *uid != folded {
+            let filed: Option<String> = conn.hget(names::uid_table(), &folded)?;
+            if filed.as_ref() == Some(userhash) {
+                continue;
+            }
+        }
+
+        users.push(UserSummary {
+            username,
+            balance: conn.get(names::user_balance(userhash))?,
+            is_admin: conn.exists(names::user_admin(userhash))?,
+        });
+    }
+    Ok((next, users))
+}
+
+/// Up to `count` entries of the history of a user, newest first, along with
+/// the length of the whole history. Entries are told apart by their position
+/// counting from the oldest one, which new entries don't shift around; the
+/// ones read are those right below `below`, or the newest ones if not given
+/// or past the newest one.
+pub fn history(
+    conn: &mut redis::Connection,
+    username: &str,
+    below: Option<usize>,
+    count: usize,
+) -> redis::RedisResult<(usize, Vec<String>)> {
     trace!("Attempting to get history for user {}", username);
 
     let userhash = get_userhash(conn, &username)?;
-    use redis::Commands;
 
-    conn.lrange(names::user_history(&userhash), -20, -1)
+    redis::Script::new(HISTORY_PAGE_SCRIPT)
+        .key(names::user_history(&userhash))
+        .arg(below.map(|below| below.to_string()).unwrap_or_default())
+        .arg(count)
+        .invoke(conn)
 }
 
 #[derive(Debug)]
@@ -100,6 +382,8 @@ pub enum TransactionStatus {
     InvalidFrom,
     InvalidTo,
     Cooldown,
+    /// One of the accounts involved is frozen.
+    Frozen,
 }
 
 pub fn transaction(
@@ -111,7 +395,12 @@ pub fn transaction(
     trace!("Attempting to transfer {} from {} to {}", amount, from, to);
 
     let fromhash = get_userhash(conn, &from)?;
-    let tohash = get_userhash(conn, &to)?;
+    let tohash = match find_userhash(conn, &to)? {
+        Some(tohash) => tohash,
+        None => return Ok(TransactionStatus::InvalidTo),
+    };
+    use redis::Commands;
+    let to: String = conn.get(names::user_username(&tohash))?;
 
     let script = redis::Script::new(TRANSACTION_SCRIPT);
     let code: u32 = script
@@ -121,9 +410,16 @@ pub fn transaction(
         .key(names::user_balance(&tohash))
         .key(names::user_history(&tohash))
         .key(names::user_cooldown(&tohash))
+        .key(names::user_frozen(&fromhash))
+        .key(names::user_frozen(&tohash))
+        .key(names::transaction_counter())
+        .key(names::transaction_table())
+        .key(names::ledger())
         .arg(amount)
         .arg(from)
         .arg(to)
+        .arg(&fromhash)
+        .arg(&tohash)
         .invoke(conn)?;
     let status = match code {
         0 => TransactionStatus::Success,
@@ -131,19 +427,383 @@ pub fn transaction(
         2 => TransactionStatus::InvalidFrom,
         3 => TransactionStatus::InvalidTo,
         4 => TransactionStatus::Cooldown,
+        5 => TransactionStatus::Frozen,
         _ => panic!("Invalid status code returned"),
     };
     Ok(status)
 }
 
+/// Looks up the record of the transaction with the given id.
+pub fn find_transaction(
+    conn: &mut redis::Connection,
+    id: &str,
+) -> redis::RedisResult<Option<String>> {
+    use redis::Commands;
+    conn.hget(names::transaction_table(), id)
+}
+
+/// One side of a movement of money in the ledger, taking it out of an account
+/// when negative and putting it into one when positive. Accounts are the
+/// userhashes of their owners, save for the mint and burn accounts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Posting {
+    pub account: String,
+    pub amount: i64,
+}
+
+use std::collections::BTreeMap;
+/// Balances of every account as the ledger has them.
+#[derive(Debug, Default)]
+pub struct Ledger {
+    pub balances: BTreeMap<String, i64>,
+    /// Ids of the entries whose postings don't add up to nothing, which
+    /// shouldn't ever be found in there.
+    pub unbalanced: Vec<String>,
+    /// Number of entries read.
+    pub entries: u64,
+    /// Id of the last entry read, if there were any.
+    pub last: Option<String>,
+}
+
+/// Replays the whole ledger, working out the balance of every account in it.
+pub fn read_ledger(conn: &mut redis::Connection) -> redis::RedisResult<Ledger> {
+    let mut ledger = Ledger::default();
+    let mut start = "-".to_owned();
+    loop {
+        let batch: Vec<(String, Vec<String>)> = redis::cmd("XRANGE")
+            .arg(names::ledger())
+            .arg(&start)
+            .arg("+")
+            .arg("COUNT")
+            .arg(LEDGER_BATCH_SIZE)
+            .query(conn)?;
+
+        for (id, fields) in &batch {
+            let postings = fields
+                .chunks(2)
+                .find(|pair| pair[0] == "postings")
+                .and_then(|pair| pair.get(1))
+                .and_then(|postings| serde_json::from_str::<Vec<Posting>>(postings).ok());
+            let postings = match postings {
+                Some(postings) => postings,
+                None => {
+                    ledger.unbalanced.push(id.clone());
+                    continue;
+                }
+            };
+
+            if postings.iter().map(|posting| posting.amount).sum::<i64>() != 0 {
+                ledger.unbalanced.push(id.clone());
+            }
+            for posting in postings {
+                *ledger.balances.entry(posting.account).or_insert(0) += posting.amount;
+            }
+        }
+        ledger.entries += batch.len() as u64;
+        if let Some((id, _)) = batch.last() {
+            ledger.last = Some(id.clone());
+        }
+
+        /* Ranges are inclusive, so pick up right after the last entry read. */
+        let last = match batch.last() {
+            Some((id, _)) if batch.len() == LEDGER_BATCH_SIZE => id,
+            _ => return Ok(ledger),
+        };
+        let mut parts = last.splitn(2, '-');
+        let time = parts.next().unwrap_or("0");
+        let sequence = parts
+            .next()
+            .and_then(|seq| seq.parse::<u64>().ok())
+            .unwrap_or(0);
+        start = format!("{}-{}", time, sequence + 1);
+    }
+}
+
+/// An account whose balance isn't what the ledger says it should be.
+#[derive(Debug, Clone, Serialize)]
+pub struct BalanceMismatch {
+    pub account: String,
+    /// Username of the owner, if the account is still around.
+    pub username: Option<String>,
+    /// Balance of the account, as stored.
+    pub recorded: Option<i64>,
+    /// Balance of the account, as worked out from the ledger.
+    pub computed: i64,
+}
+
+/// Checks the balance of every account against the ledger. Accounts with
+/// money that predates the ledger will show up here too, as none of it was
+/// ever journaled.
+pub fn verify_ledger(
+    conn: &mut redis::Connection,
+) -> redis::RedisResult<(Ledger, Vec<BalanceMismatch>)> {
+    let ledger = read_ledger(conn)?;
+
+    use redis::Commands;
+    use std::collections::{HashMap, HashSet};
+    let uids: HashMap<String, String> = conn.hgetall(names::uid_table())?;
+    let mut mismatches = Vec::new();
+    let mut seen = HashSet::new();
+    for userhash in uids.values() {
+        if !seen.insert(userhash.clone()) {
+            continue;
+        }
+
+        let recorded: Option<String> = conn.get(names::user_balance(userhash))?;
+        let recorded = recorded.and_then(|balance| balance.parse::<i64>().ok());
+        let computed = ledger.balances.get(userhash).cloned().unwrap_or(0);
+        if recorded != Some(computed) {
+            mismatches.push(BalanceMismatch {
+                account: userhash.clone(),
+                username: conn.get(names::user_username(userhash))?,
+                recorded,
+                computed,
+            });
+        }
+    }
+
+    /* Money can't be left behind in accounts that are gone. */
+    for (account, computed) in &ledger.balances {
+        if *computed != 0
+            && account != MINT_ACCOUNT
+            && account != BURN_ACCOUNT
+            && !seen.contains(account)
+        {
+            mismatches.push(BalanceMismatch {
+                account: account.clone(),
+                username: None,
+                recorded: None,
+                computed: *computed,
+            });
+        }
+    }
+
+    Ok((ledger, mismatches))
+}
+
+use serde_derive::{Deserialize, Serialize};
+/// A login session, as stored in the user's token table under its id.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Session {
+    /// Nonce of the one refresh token currently valid for this session.
+    pub nonce: String,
+    /// Unix timestamp after which the session can no longer be refreshed.
+    pub expires: i64,
+    /// Unix timestamp of the login that opened the session.
+    #[serde(default)]
+    pub created: i64,
+    /// Unix timestamp of the last request made with the session.
+    #[serde(default)]
+    pub last_used: i64,
+    /// Address the session was opened from, if it was known.
+    #[serde(default)]
+    pub ip: Option<String>,
+    /// User agent the session was opened with, if one was sent.
+    #[serde(default)]
+    pub user_agent: Option<String>,
+}
+
+/// Drops every session of the user that can no longer be refreshed, going by
+/// the clock of the database like `touch_session` does, and returns the rest.
+fn prune_sessions(
+    conn: &mut redis::Connection,
+    userhash: &str,
+) -> redis::RedisResult<Vec<(String, Session)>> {
+    use redis::Commands;
+    use std::collections::HashMap;
+
+    let (now, _): (i64, i64) = redis::cmd("TIME").query(conn)?;
+    let sessions: HashMap<String, String> = conn.hgetall(names::user_tokens(userhash))?;
+    let mut live = Vec::with_capacity(sessions.len());
+    for (id, session) in sessions {
+        let session = serde_json::from_str::<Session>(&session)
+            .ok()
+            .filter(|session| session.expires >= now);
+        match session {
+            Some(session) => live.push((id, session)),
+            None => {
+                trace!("Pruning expired session {} on userhash {}", id, userhash);
+                conn.hdel(names::user_tokens(userhash), id)?;
+            }
+        }
+    }
+    Ok(live)
+}
+
+pub fn create_session(
+    conn: &mut redis::Connection,
+    username: &str,
+    id: &str,
+    session: &Session,
+) -> redis::RedisResult<()> {
+    let userhash = get_userhash(conn, username)?;
+    prune_sessions(conn, &userhash)?;
+
+    let session = serde_json::to_string(session).expect("Sessions are always serializable");
+
+    use redis::Commands;
+    conn.hset(names::user_tokens(&userhash), id, session)
+}
+
+#[derive(Debug)]
+pub enum RefreshStatus {
+    Success,
+    /// The session expired, was revoked or never existed.
+    Revoked,
+    /// A refresh token that had already been rotated out was presented again,
+    /// so the whole session got revoked.
+    Reused,
+}
+
+/// Rotates the refresh token of a session, provided the one being presented
+/// is the latest one handed out.
+pub fn refresh_session(
+    conn: &mut redis::Connection,
+    username: &str,
+    id: &str,
+    nonce: &str,
+    renewed: &Session,
+) -> redis::RedisResult<RefreshStatus> {
+    let userhash = match find_userhash(conn, username)? {
+        Some(userhash) => userhash,
+        None => return Ok(RefreshStatus::Revoked),
+    };
+
+    let code: u32 = redis::Script::new(REFRESH_SCRIPT)
+        .key(names::user_tokens(&userhash))
+        .arg(id)
+        .arg(nonce)
+        .arg(&renewed.nonce)
+        .arg(renewed.expires)
+        .arg(chrono::Utc::now().timestamp())
+        .invoke(conn)?;
+    Ok(match code {
+        0 => RefreshStatus::Success,
+        1 => RefreshStatus::Revoked,
+        2 => RefreshStatus::Reused,
+        _ => panic!("Invalid status code returned"),
+    })
+}
+
+/// Checks whether the session is still around, marking it as used just now
+/// if it is.
+pub fn touch_session(
+    conn: &mut redis::Connection,
+    username: &str,
+    id: &str,
+) -> redis::RedisResult<bool> {
+    let userhash = match find_userhash(conn, username)? {
+        Some(userhash) => userhash,
+        None => return Ok(false),
+    };
+
+    redis::Script::new(TOUCH_SESSION_SCRIPT)
+        .key(names::user_tokens(&userhash))
+        .arg(id)
+        .invoke(conn)
+}
+
+/// Lists the sessions of the user that can still be refreshed, most recently
+/// used first.
+pub fn list_sessions(
+    conn: &mut redis::Connection,
+    username: &str,
+) -> redis::RedisResult<Vec<(String, Session)>> {
+    let userhash = get_userhash(conn, username)?;
+    let mut sessions = prune_sessions(conn, &userhash)?;
+    sessions.sort_by(|(_, a), (_, b)| b.last_used.cmp(&a.last_used));
+
+    Ok(sessions)
+}
+
+/// Revokes one session of the user, returning whether there was such a
+/// session to begin with.
+pub fn revoke_session(
+    conn: &mut redis::Connection,
+    username: &str,
+    id: &str,
+) -> redis::RedisResult<bool> {
+    let userhash = get_userhash(conn, username)?;
+    trace!("Revoking session {} on userhash {}", id, userhash);
+
+    use redis::Commands;
+    let removed: u32 = conn.hdel(names::user_tokens(&userhash), id)?;
+    Ok(removed > 0)
+}
+
+pub fn revoke_all_sessions(conn: &mut redis::Connection, username: &str) -> redis::RedisResult<()> {
+    let userhash = get_userhash(conn, username)?;
+    trace!("Revoking all sessions on userhash {}", userhash);
+
+    use redis::Commands;
+    conn.del(names::user_tokens(&userhash))
+}
+
 use crate::api::Balance;
+use crate::naming;
+use crate::settings;
+/// Something done through the admin endpoints, as recorded in the audit log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub time: i64,
+    pub actor: String,
+    pub action: String,
+    pub target: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+pub fn audit(
+    conn: &mut redis::Connection,
+    actor: &str,
+    action: &str,
+    target: &str,
+    detail: Option<String>,
+) -> redis::RedisResult<()> {
+    let entry = serde_json::to_string(&AuditEntry {
+        time: chrono::Utc::now().timestamp(),
+        actor: actor.to_owned(),
+        action: action.to_owned(),
+        target: target.to_owned(),
+        detail,
+    })
+    .expect("Audit entries are always serializable");
+
+    use redis::Commands;
+    conn.lpush(names::audit_log(), entry)
+}
+
+/// Reads a page of the audit log, newest entries first.
+pub fn audit_log(
+    conn: &mut redis::Connection,
+    start: usize,
+    count: usize,
+) -> redis::RedisResult<Vec<AuditEntry>> {
+    use redis::Commands;
+    let entries: Vec<String> = conn.lrange(
+        names::audit_log(),
+        start as isize,
+        (start + count) as isize - 1,
+    )?;
+
+    Ok(entries
+        .into_iter()
+        .filter_map(|entry| match serde_json::from_str(&entry) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                warn!("Skipping malformed audit entry {}: {}", entry, e);
+                None
+            }
+        })
+        .collect())
+}
+
 pub fn create_account(
     connection: &mut redis::Connection,
     username: String,
     email: String,
     realname: String,
     keyhash: String,
-    salt: String,
 ) -> redis::RedisResult<String> {
     let script = redis::Script::new(NEW_ACCOUNT_SCRIPT);
     for _ in (0..MAX_RETRIES) {
@@ -164,13 +824,22 @@ pub fn create_account(
             .key(names::user_balance(&userhash))
             .key(names::uid_table())
             .key(names::user_username(&userhash))
+            .key(names::user_created(&userhash))
+            .key(names::ledger())
+            .key(names::user_history(&userhash))
+            .key(names::transaction_counter())
+            .key(names::transaction_table())
             .arg(INITIAL_BALANCE)
             .arg(&email)
             .arg(&realname)
             .arg(&keyhash)
-            .arg(&salt)
+            .arg(NO_SALT)
             .arg(&username)
             .arg(&userhash)
+            .arg(naming::fold(&username))
+            .arg(chrono::Utc::now().timestamp())
+            .arg(MINT_ACCOUNT)
+            .arg(SYSTEM_ACTOR)
             .invoke(connection)?;
 
         if result.as_str() != "-Retry" {
@@ -182,15 +851,42 @@ pub fn create_account(
     Ok("-UnableToCreate".to_owned())
 }
 
+#[derive(Debug)]
+pub enum DeleteStatus {
+    /// The account is gone, and this much was handed over to the beneficiary.
+    Success(Balance),
+    /// The account still holds this much and no beneficiary was given.
+    BalanceOutstanding(Balance),
+    InvalidBeneficiary,
+    /// The account is frozen, and so are the funds in it.
+    Frozen,
+    NotFound,
+}
+
 pub fn delete_account(
     connection: &mut redis::Connection,
     username: String,
-) -> redis::RedisResult<String> {
-    let userhash = get_userhash(connection, &username)?;
+    beneficiary: Option<String>,
+) -> redis::RedisResult<DeleteStatus> {
+    let userhash = match find_userhash(connection, &username)? {
+        Some(userhash) => userhash,
+        None => return Ok(DeleteStatus::NotFound),
+    };
+    let benefhash = match beneficiary {
+        Some(ref beneficiary) => match find_userhash(connection, beneficiary)? {
+            Some(ref benefhash) if *benefhash == userhash => {
+                return Ok(DeleteStatus::InvalidBeneficiary)
+            }
+            Some(benefhash) => Some(benefhash),
+            None => return Ok(DeleteStatus::InvalidBeneficiary),
+        },
+        None => None,
+    };
     trace!("Deleting the account on userhash {}", userhash);
 
     let script = redis::Script::new(DEL_ACCOUNT_SCRIPT);
-    script
+    let mut invocation = script.prepare_invoke();
+    invocation
         .key(names::user_email(&userhash))
         .key(names::user_name(&userhash))
         .key(names::user_history(&userhash))
@@ -201,14 +897,60 @@ pub fn delete_account(
         .key(names::user_balance(&userhash))
         .key(names::user_username(&userhash))
         .key(names::uid_table())
-        .invoke(connection)
+        .key(names::user_admin(&userhash))
+        .key(names::user_roles(&userhash))
+        .key(names::user_totp(&userhash))
+        .key(names::user_recovery(&userhash))
+        .key(names::user_api_keys(&userhash))
+        .key(names::api_key_table())
+        .key(names::user_email_verified(&userhash))
+        .key(names::user_reset(&userhash))
+        .key(names::user_created(&userhash))
+        .key(names::user_frozen(&userhash))
+        .key(names::transaction_counter())
+        .key(names::transaction_table())
+        .key(names::ledger())
+        .arg(&userhash)
+        .arg(naming::fold(&username));
+    if let (Some(benefhash), Some(beneficiary)) = (benefhash, beneficiary) {
+        invocation
+            .key(names::user_balance(&benefhash))
+            .key(names::user_history(&benefhash))
+            .key(names::user_frozen(&benefhash))
+            .arg(beneficiary)
+            .arg(&benefhash);
+    }
+
+    let (status, amount): (String, Balance) = invocation.invoke(connection)?;
+    Ok(match status.as_str() {
+        "+OK" => DeleteStatus::Success(amount),
+        "-BalanceOutstanding" => DeleteStatus::BalanceOutstanding(amount),
+        "-InvalidBeneficiary" => DeleteStatus::InvalidBeneficiary,
+        "-Frozen" => DeleteStatus::Frozen,
+        "-KeyDoesNotExist" => DeleteStatus::NotFound,
+        s => panic!("Invalid status returned by account deletion: {}", s),
+    })
+}
+
+#[derive(Debug)]
+pub enum ValidateError {
+    Redis(redis::RedisError),
+    /// The keyhash stored for the user can't be verified against at all.
+    Corrupted(keyhash::VerifyError),
+}
+impl From<redis::RedisError> for ValidateError {
+    fn from(what: redis::RedisError) -> ValidateError {
+        ValidateError::Redis(what)
+    }
 }
 
+use crate::keyhash::{self, PasswordHasher};
 pub fn validate(
     connection: &mut redis::Connection,
     username: String,
     password: String,
-) -> redis::RedisResult<bool> {
+    hasher: &dyn PasswordHasher,
+) -> Result<bool, ValidateError> {
     trace!("Validating credentials for user {}", username);
 
     let userhash = match get_userhash(connection, &username) {
@@ -218,13 +960,118 @@ pub fn validate(
 
     use redis::Commands;
     let hash: Option<String> = connection.get(names::user_keyhash(&userhash))?;
-    let salt: Option<String> = connection.get(names::user_salt(&userhash))?;
     trace!("Trying to log in user with hash {}", userhash);
-    use crate::keyhash;
-    Ok(match (hash, salt) {
-        (Some(hash), Some(salt)) => keyhash::verify(password, hash, salt).unwrap_or(false),
-        _ => false,
-    })
+    let hash = match hash {
+        Some(hash) => hash,
+        None => return Ok(false),
+    };
+
+    match keyhash::verify(&password, &hash) {
+        Ok(true) => (),
+        Ok(false) => return Ok(false),
+        Err(what) => {
+            warn!(
+                "The keyhash of {} on userhash {} is corrupted: {:?}",
+                username, userhash, what
+            );
+            return Err(ValidateError::Corrupted(what));
+        }
+    }
+
+    if !hasher.is_current(&hash) {
+        match hasher.generate(&password) {
+            Ok(hash) => {
+                info!("Upgrading the keyhash of {}", username);
+                connection.set(names::user_keyhash(&userhash), hash)?;
+            }
+            Err(what) => warn!("Could not upgrade the keyhash of {}: {:?}", username, what),
+        }
+    }
+    Ok(true)
+}
+
+/// Replaces the keyhash of the user, revoking every session but the one given
+/// along with every API key. Returns how many API keys were revoked.
+pub fn change_key(
+    conn: &mut redis::Connection,
+    username: &str,
+    keyhash: String,
+    keep_session: Option<&str>,
+) -> redis::RedisResult<u32> {
+    let userhash = get_userhash(conn, username)?;
+    trace!("Changing the key on userhash {}", userhash);
+
+    redis::Script::new(CHANGE_KEY_SCRIPT)
+        .key(names::user_keyhash(&userhash))
+        .key(names::user_salt(&userhash))
+        .key(names::user_tokens(&userhash))
+        .key(names::user_api_keys(&userhash))
+        .key(names::api_key_table())
+        .arg(keyhash)
+        .arg(NO_SALT)
+        .arg(keep_session.unwrap_or(""))
+        .invoke(conn)
+}
+
+/// Files a token for resetting the key of the user under the digest of the
+/// token, in place of whichever one the user had been sent before.
+pub fn create_password_reset(
+    conn: &mut redis::Connection,
+    digest: &str,
+    username: &str,
+    lifetime: u64,
+) -> redis::RedisResult<()> {
+    let userhash = get_userhash(conn, username)?;
+
+    use redis::Commands;
+    let previous: Option<String> = conn.get(names::user_reset(&userhash))?;
+
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    if let Some(previous) = previous {
+        pipe.cmd("DEL")
+            .arg(names::password_reset(&previous))
+            .ignore();
+    }
+    pipe.cmd("SET")
+        .arg(names::password_reset(digest))
+        .arg(username)
+        .arg("EX")
+        .arg(lifetime)
+        .ignore()
+        .cmd("SET")
+        .arg(names::user_reset(&userhash))
+        .arg(digest)
+        .arg("EX")
+        .arg(lifetime)
+        .ignore()
+        .query(conn)
+}
+
+/// Uses up the reset token filed under the digest. Yields the user it belongs
+/// to, or `None` if the token doesn't exist, expired or was replaced by a
+/// newer one.
+pub fn use_password_reset(
+    conn: &mut redis::Connection,
+    digest: &str,
+) -> redis::RedisResult<Option<String>> {
+    use redis::Commands;
+    let username: Option<String> = conn.get(names::password_reset(digest))?;
+    let username = match username {
+        Some(username) => username,
+        None => return Ok(None),
+    };
+    let userhash = match find_userhash(conn, &username)? {
+        Some(userhash) => userhash,
+        None => return Ok(None),
+    };
+
+    let used: bool = redis::Script::new(PASSWORD_RESET_SCRIPT)
+        .key(names::password_reset(digest))
+        .key(names::user_reset(&userhash))
+        .arg(digest)
+        .invoke(conn)?;
+    Ok(if used { Some(username) } else { None })
 }
 
 pub fn is_admin(conn: &mut redis::Connection, username: String) -> redis::RedisResult<bool> {
@@ -234,31 +1081,916 @@ pub fn is_admin(conn: &mut redis::Connection, username: String) -> redis::RedisR
     conn.exists(names::user_admin(&userhash))
 }
 
+/// Sets or clears the admin flag of the user, returning whether it changed.
+pub fn set_admin(
+    conn: &mut redis::Connection,
+    username: &str,
+    admin: bool,
+) -> redis::RedisResult<bool> {
+    let userhash = get_userhash(conn, username)?;
+
+    use redis::Commands;
+    if admin {
+        conn.set_nx(names::user_admin(&userhash), "1")
+    } else {
+        let removed: u32 = conn.del(names::user_admin(&userhash))?;
+        Ok(removed > 0)
+    }
+}
+
+pub fn user_roles(conn: &mut redis::Connection, username: &str) -> redis::RedisResult<Vec<String>> {
+    let userhash = get_userhash(conn, username)?;
+
+    use redis::Commands;
+    conn.smembers(names::user_roles(&userhash))
+}
+
+/// Grants a role to the user, returning whether they didn't have it already.
+pub fn grant_role(
+    conn: &mut redis::Connection,
+    username: &str,
+    role: &str,
+) -> redis::RedisResult<bool> {
+    let userhash = get_userhash(conn, username)?;
+
+    use redis::Commands;
+    let added: u32 = conn.sadd(names::user_roles(&userhash), role)?;
+    Ok(added > 0)
+}
+
+/// Revokes a role from the user, returning whether they had it at all.
+pub fn revoke_role(
+    conn: &mut redis::Connection,
+    username: &str,
+    role: &str,
+) -> redis::RedisResult<bool> {
+    let userhash = get_userhash(conn, username)?;
+
+    use redis::Commands;
+    let removed: u32 = conn.srem(names::user_roles(&userhash), role)?;
+    Ok(removed > 0)
+}
+
+/// Two-factor authentication setup of a user.
+#[derive(Debug)]
+pub struct TotpState {
+    pub secret: String,
+    /// Whether the secret has been confirmed, as opposed to just generated.
+    pub enabled: bool,
+}
+
+pub fn totp_state(
+    conn: &mut redis::Connection,
+    username: &str,
+) -> redis::RedisResult<Option<TotpState>> {
+    let userhash = get_userhash(conn, username)?;
+
+    let (secret, enabled): (Option<String>, Option<String>) = redis::cmd("HMGET")
+        .arg(names::user_totp(&userhash))
+        .arg("secret")
+        .arg("enabled")
+        .query(conn)?;
+    Ok(secret.map(|secret| TotpState {
+        secret,
+        enabled: enabled.as_ref().map(String::as_str) == Some("1"),
+    }))
+}
+
+/// Sets up a secret waiting to be confirmed, replacing any other such secret.
+pub fn set_pending_totp(
+    conn: &mut redis::Connection,
+    username: &str,
+    secret: &str,
+) -> redis::RedisResult<()> {
+    let userhash = get_userhash(conn, username)?;
+
+    redis::cmd("HMSET")
+        .arg(names::user_totp(&userhash))
+        .arg("secret")
+        .arg(secret)
+        .arg("enabled")
+        .arg("0")
+        .arg("last_step")
+        .arg(0)
+        .query(conn)
+}
+
+/// Confirms the pending secret, at the step of the code that confirmed it, and
+/// replaces the recovery codes of the user.
+pub fn enable_totp(
+    conn: &mut redis::Connection,
+    username: &str,
+    step: u64,
+    recovery_hashes: &[String],
+) -> redis::RedisResult<()> {
+    let userhash = get_userhash(conn, username)?;
+
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .cmd("HMSET")
+        .arg(names::user_totp(&userhash))
+        .arg("enabled")
+        .arg("1")
+        .arg("last_step")
+        .arg(step)
+        .ignore()
+        .cmd("DEL")
+        .arg(names::user_recovery(&userhash))
+        .ignore();
+    if !recovery_hashes.is_empty() {
+        pipe.cmd("SADD")
+            .arg(names::user_recovery(&userhash))
+            .arg(recovery_hashes)
+            .ignore();
+    }
+    pipe.query(conn)
+}
+
+pub fn disable_totp(conn: &mut redis::Connection, username: &str) -> redis::RedisResult<()> {
+    let userhash = get_userhash(conn, username)?;
+
+    use redis::Commands;
+    conn.del(&[names::user_totp(&userhash), names::user_recovery(&userhash)][..])
+}
+
+/// Marks the TOTP step as used, returning whether it was newer than the last
+/// one used, that is, whether its code may be accepted.
+pub fn use_totp_step(
+    conn: &mut redis::Connection,
+    username: &str,
+    step: u64,
+) -> redis::RedisResult<bool> {
+    let userhash = get_userhash(conn, username)?;
+
+    let code: u32 = redis::Script::new(TOTP_STEP_SCRIPT)
+        .key(names::user_totp(&userhash))
+        .arg(step)
+        .invoke(conn)?;
+    Ok(code == 1)
+}
+
+/// Spends a recovery code, returning whether it was there to be spent.
+pub fn use_recovery_code(
+    conn: &mut redis::Connection,
+    username: &str,
+    hash: &str,
+) -> redis::RedisResult<bool> {
+    let userhash = get_userhash(conn, username)?;
+
+    use redis::Commands;
+    let removed: u32 = conn.srem(names::user_recovery(&userhash), hash)?;
+    Ok(removed > 0)
+}
+
+/// The email address of the user, along with whether it has been verified.
+pub fn email_status(
+    conn: &mut redis::Connection,
+    username: &str,
+) -> redis::RedisResult<(String, bool)> {
+    let userhash = get_userhash(conn, username)?;
+
+    use redis::Commands;
+    Ok((
+        conn.get(names::user_email(&userhash))?,
+        conn.exists(names::user_email_verified(&userhash))?,
+    ))
+}
+
+/// Changes the display name and email address of the user, leaving alone
+/// whichever isn't given. A new email address has yet to be verified, and takes
+/// any password reset sent to the old one with it.
+pub fn update_profile(
+    conn: &mut redis::Connection,
+    username: &str,
+    realname: Option<&str>,
+    email: Option<&str>,
+) -> redis::RedisResult<()> {
+    let userhash = get_userhash(conn, username)?;
+
+    use redis::Commands;
+    let reset: Option<String> = match email {
+        Some(_) => conn.get(names::user_reset(&userhash))?,
+        None => None,
+    };
+
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    if let Some(realname) = realname {
+        pipe.cmd("SET")
+            .arg(names::user_name(&userhash))
+            .arg(realname)
+            .ignore();
+    }
+    if let Some(email) = email {
+        pipe.cmd("SET")
+            .arg(names::user_email(&userhash))
+            .arg(email)
+            .ignore()
+            .cmd("DEL")
+            .arg(names::user_email_verified(&userhash))
+            .ignore();
+
+        /* Reset tokens went to the old address. Tokens only work for as long
+         * as the user still points at them, so that goes even for one that
+         * slips in after the lookup above. */
+        pipe.cmd("DEL").arg(names::user_reset(&userhash)).ignore();
+        if let Some(reset) = reset {
+            pipe.cmd("DEL").arg(names::password_reset(&reset)).ignore();
+        }
+    }
+    pipe.query(conn)
+}
+
+/// Files a token for verifying the email address of the user, under the
+/// digest of the token.
+pub fn create_email_verification(
+    conn: &mut redis::Connection,
+    digest: &str,
+    username: &str,
+    email: &str,
+    lifetime: u64,
+) -> redis::RedisResult<()> {
+    redis::pipe()
+        .atomic()
+        .cmd("HSET")
+        .arg(names::email_verification(digest))
+        .arg("username")
+        .arg(username)
+        .arg("email")
+        .arg(email)
+        .ignore()
+        .cmd("EXPIRE")
+        .arg(names::email_verification(digest))
+        .arg(lifetime)
+        .ignore()
+        .query(conn)
+}
+
+/// Uses up the token filed under the digest, marking the email address it was
+/// issued for as verified. Yields the user it belongs to, or `None` if the
+/// token doesn't exist, expired or is for an address the user no longer has.
+pub fn verify_email(
+    conn: &mut redis::Connection,
+    digest: &str,
+) -> redis::RedisResult<Option<String>> {
+    let (username, email): (Option<String>, Option<String>) = redis::cmd("HMGET")
+        .arg(names::email_verification(digest))
+        .arg("username")
+        .arg("email")
+        .query(conn)?;
+    let (username, email) = match (username, email) {
+        (Some(username), Some(email)) => (username, email),
+        _ => return Ok(None),
+    };
+    let userhash = match find_userhash(conn, &username)? {
+        Some(userhash) => userhash,
+        None => return Ok(None),
+    };
+
+    let verified: bool = redis::Script::new(VERIFY_EMAIL_SCRIPT)
+        .key(names::email_verification(digest))
+        .key(names::user_email(&userhash))
+        .key(names::user_email_verified(&userhash))
+        .arg(email)
+        .invoke(conn)?;
+    Ok(if verified { Some(username) } else { None })
+}
+
+/// Opens a login challenge, waiting for the second factor of the user.
+pub fn create_challenge(
+    conn: &mut redis::Connection,
+    id: &str,
+    username: &str,
+    lifetime: u64,
+) -> redis::RedisResult<()> {
+    redis::pipe()
+        .atomic()
+        .cmd("HSET")
+        .arg(names::login_challenge(id))
+        .arg("username")
+        .arg(username)
+        .ignore()
+        .cmd("EXPIRE")
+        .arg(names::login_challenge(id))
+        .arg(lifetime)
+        .ignore()
+        .query(conn)
+}
+
+pub fn challenge_username(
+    conn: &mut redis::Connection,
+    id: &str,
+) -> redis::RedisResult<Option<String>> {
+    use redis::Commands;
+    conn.hget(names::login_challenge(id), "username")
+}
+
+/// Counts a failed attempt at the challenge, returning how many there were,
+/// or `None` if the challenge is gone.
+pub fn fail_challenge(conn: &mut redis::Connection, id: &str) -> redis::RedisResult<Option<u32>> {
+    redis::Script::new(FAIL_CHALLENGE_SCRIPT)
+        .key(names::login_challenge(id))
+        .invoke(conn)
+}
+
+pub fn drop_challenge(conn: &mut redis::Connection, id: &str) -> redis::RedisResult<()> {
+    use redis::Commands;
+    conn.del(names::login_challenge(id))
+}
+
+/// What an API key may be used for. Keys can't do anything they weren't
+/// scoped for, and never more than their user could.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "scope")]
+pub enum ApiScope {
+    /// Reading the account, through `/info` and `/history`.
+    ReadOnly,
+    /// Making transfers, up to this much in total per day.
+    Transfer { daily_limit: Balance },
+    /// Depositing through `/admin/deposit`.
+    AdminDeposit,
+}
+impl ApiScope {
+    pub fn daily_limit(&self) -> Option<Balance> {
+        match *self {
+            ApiScope::Transfer { daily_limit } => Some(daily_limit),
+            _ => None,
+        }
+    }
+}
+
+/// An API key, as stored in the user's API key table under its id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    /// SHA-256 digest of the secret half of the key.
+    pub secret_hash: String,
+    pub scopes: Vec<ApiScope>,
+    pub created: i64,
+    /// Unix timestamp after which the key is no longer accepted, if ever.
+    pub expires: Option<i64>,
+}
+impl ApiKey {
+    pub fn expired(&self, now: i64) -> bool {
+        self.expires.map(|expires| expires < now).unwrap_or(false)
+    }
+
+    /// Most the key may transfer per day, if it may transfer at all.
+    pub fn daily_limit(&self) -> Option<Balance> {
+        self.scopes.iter().filter_map(ApiScope::daily_limit).max()
+    }
+}
+
+pub fn create_api_key(
+    conn: &mut redis::Connection,
+    username: &str,
+    key: &ApiKey,
+) -> redis::RedisResult<()> {
+    let userhash = get_userhash(conn, username)?;
+
+    /* Drop the keys that expired while we're at it. */
+    let now = chrono::Utc::now().timestamp();
+    for expired in list_api_keys(conn, username)?
+        .into_iter()
+        .filter(|key| key.expired(now))
+    {
+        trace!(
+            "Pruning expired API key {} on userhash {}",
+            expired.id,
+            userhash
+        );
+        revoke_api_key(conn, username, &expired.id)?;
+    }
+
+    let json = serde_json::to_string(key).expect("API keys are always serializable");
+    redis::pipe()
+        .atomic()
+        .cmd("HSET")
+        .arg(names::user_api_keys(&userhash))
+        .arg(&key.id)
+        .arg(json)
+        .ignore()
+        .cmd("HSET")
+        .arg(names::api_key_table())
+        .arg(&key.id)
+        .arg(username)
+        .ignore()
+        .query(conn)
+}
+
+pub fn list_api_keys(
+    conn: &mut redis::Connection,
+    username: &str,
+) -> redis::RedisResult<Vec<ApiKey>> {
+    let userhash = get_userhash(conn, username)?;
+
+    use redis::Commands;
+    let keys: Vec<String> = conn.hvals(names::user_api_keys(&userhash))?;
+    Ok(keys
+        .into_iter()
+        .filter_map(|key| match serde_json::from_str(&key) {
+            Ok(key) => Some(key),
+            Err(e) => {
+                warn!("Skipping malformed API key {}: {}", key, e);
+                None
+            }
+        })
+        .collect())
+}
+
+/// Looks an API key up by its id, along with the user it belongs to.
+pub fn find_api_key(
+    conn: &mut redis::Connection,
+    id: &str,
+) -> redis::RedisResult<Option<(String, ApiKey)>> {
+    use redis::Commands;
+    let username: Option<String> = conn.hget(names::api_key_table(), id)?;
+    let username = match username {
+        Some(username) => username,
+        None => return Ok(None),
+    };
+    let userhash = match find_userhash(conn, &username)? {
+        Some(userhash) => userhash,
+        None => return Ok(None),
+    };
+
+    let key: Option<String> = conn.hget(names::user_api_keys(&userhash), id)?;
+    Ok(key
+        .and_then(|key| serde_json::from_str(&key).ok())
+        .map(|key| (username, key)))
+}
+
+/// Revokes an API key of the user, returning whether it was there to revoke.
+pub fn revoke_api_key(
+    conn: &mut redis::Connection,
+    username: &str,
+    id: &str,
+) -> redis::RedisResult<bool> {
+    let userhash = get_userhash(conn, username)?;
+    trace!("Revoking API key {} on userhash {}", id, userhash);
+
+    let (removed, _): (u32, u32) = redis::pipe()
+        .atomic()
+        .cmd("HDEL")
+        .arg(names::user_api_keys(&userhash))
+        .arg(id)
+        .cmd("HDEL")
+        .arg(names::api_key_table())
+        .arg(id)
+        .query(conn)?;
+    Ok(removed > 0)
+}
+
+/// Counts a transfer against the daily limit of an API key, returning the day
+/// it was counted on if it fit under the limit. Nothing is counted when it
+/// doesn't.
+pub fn spend_api_key(
+    conn: &mut redis::Connection,
+    id: &str,
+    amount: Balance,
+    limit: Balance,
+) -> redis::RedisResult<Option<String>> {
+    let day = chrono::Utc::now().format("%Y-%m-%d").to_string();
+
+    let code: u32 = redis::Script::new(API_KEY_SPEND_SCRIPT)
+        .key(names::api_key_spent(id, &day))
+        .arg(amount)
+        .arg(limit)
+        .arg(API_KEY_SPENT_LIFETIME)
+        .invoke(conn)?;
+    Ok(if code == 0 { Some(day) } else { None })
+}
+
+/// Takes back what was counted for a transfer that didn't go through, on the
+/// day `spend_api_key()` counted it on.
+pub fn refund_api_key(
+    conn: &mut redis::Connection,
+    id: &str,
+    day: &str,
+    amount: Balance,
+) -> redis::RedisResult<()> {
+    let _: u32 = redis::Script::new(API_KEY_REFUND_SCRIPT)
+        .key(names::api_key_spent(id, day))
+        .arg(amount)
+        .invoke(conn)?;
+    Ok(())
+}
+
+/// What failed logins are counted against.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockoutKind {
+    /// The username being logged into.
+    User,
+    /// The address the logins come from.
+    Ip,
+}
+impl LockoutKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            LockoutKind::User => "user",
+            LockoutKind::Ip => "ip",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<LockoutKind> {
+        match name {
+            "user" => Some(LockoutKind::User),
+            "ip" => Some(LockoutKind::Ip),
+            _ => None,
+        }
+    }
+
+    /// Key failed logins against the subject are counted under. Usernames are
+    /// folded, so that trying other spellings of one doesn't get around it.
+    fn key(self, subject: &str) -> String {
+        match self {
+            LockoutKind::User => names::lockout(self.name(), &naming::fold(subject)),
+            LockoutKind::Ip => names::lockout(self.name(), subject),
+        }
+    }
+}
+
+/// Failed logins counted against a username or address.
+#[derive(Debug, Clone, Serialize)]
+pub struct Lockout {
+    pub kind: LockoutKind,
+    pub subject: String,
+    pub failures: u32,
+    /// Unix timestamp until which logins are refused, zero if they never were.
+    pub locked_until: i64,
+}
+
+pub fn lockout(
+    conn: &mut redis::Connection,
+    kind: LockoutKind,
+    subject: &str,
+) -> redis::RedisResult<Lockout> {
+    let (failures, locked_until): (Option<u32>, Option<i64>) = redis::cmd("HMGET")
+        .arg(kind.key(subject))
+        .arg("failures")
+        .arg("locked_until")
+        .query(conn)?;
+    Ok(Lockout {
+        kind,
+        subject: subject.to_owned(),
+        failures: failures.unwrap_or(0),
+        locked_until: locked_until.unwrap_or(0),
+    })
+}
+
+/// Counts a failed login, returning the time logins are locked out until, or
+/// zero if they aren't locked out yet.
+pub fn fail_login(
+    conn: &mut redis::Connection,
+    kind: LockoutKind,
+    subject: &str,
+    policy: &settings::Lockout,
+) -> redis::RedisResult<i64> {
+    let free_attempts = match kind {
+        LockoutKind::User => policy.free_attempts,
+        LockoutKind::Ip => policy.ip_free_attempts,
+    };
+
+    redis::Script::new(LOGIN_FAILURE_SCRIPT)
+        .key(kind.key(subject))
+        .arg(chrono::Utc::now().timestamp())
+        .arg(free_attempts)
+        .arg(policy.base_delay)
+        .arg(policy.max_delay)
+        .arg(policy.window)
+        .invoke(conn)
+}
+
+/// Forgets the failed logins counted against the subject, returning whether
+/// there were any.
+pub fn clear_lockout(
+    conn: &mut redis::Connection,
+    kind: LockoutKind,
+    subject: &str,
+) -> redis::RedisResult<bool> {
+    use redis::Commands;
+    let removed: u32 = conn.del(kind.key(subject))?;
+    Ok(removed > 0)
+}
+
+/// Lists a page of the failed login counters, starting at the given `SCAN`
+/// cursor, the same way `list_users()` does.
+pub fn list_lockouts(
+    conn: &mut redis::Connection,
+    cursor: u64,
+    count: usize,
+) -> redis::RedisResult<(u64, Vec<Lockout>)> {
+    let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+        .arg(cursor)
+        .arg("MATCH")
+        .arg(names::lockout("*", "*"))
+        .arg("COUNT")
+        .arg(count)
+        .query(conn)?;
+
+    let mut lockouts = Vec::with_capacity(keys.len());
+    for key in keys {
+        let mut fields = key.splitn(3, ':').skip(1);
+        let kind = fields.next().and_then(LockoutKind::from_name);
+        match (kind, fields.next()) {
+            (Some(kind), Some(subject)) => lockouts.push(lockout(conn, kind, subject)?),
+            _ => warn!("Skipping malformed lockout key {}", key),
+        }
+    }
+    Ok((next, lockouts))
+}
+
+/// Puts money into the account on behalf of the actor, returning the
+/// resulting balance.
 pub fn deposit(
     conn: &mut redis::Connection,
     username: String,
     amount: u32,
-) -> redis::RedisResult<()> {
+    actor: &str,
+) -> redis::RedisResult<i64> {
     let userhash = get_userhash(conn, &username)?;
-
     use redis::Commands;
-    conn.incr(names::user_balance(&userhash), amount)
+    let username: String = conn.get(names::user_username(&userhash))?;
+
+    let script = redis::Script::new(DEPOSIT_SCRIPT);
+    script
+        .key(names::user_balance(&userhash))
+        .key(names::user_history(&userhash))
+        .key(names::transaction_counter())
+        .key(names::transaction_table())
+        .key(names::ledger())
+        .arg(amount)
+        .arg(username)
+        .arg(actor)
+        .arg(&userhash)
+        .arg(MINT_ACCOUNT)
+        .invoke(conn)
 }
 
+/// Takes money out of the account on behalf of the actor. Admins may take it
+/// out of frozen accounts too, which their owners may not.
 pub fn withdraw(
     conn: &mut redis::Connection,
     username: String,
     amount: u32,
-) -> redis::RedisResult<bool> {
+    actor: &str,
+    by_admin: bool,
+) -> redis::RedisResult<TransactionStatus> {
     let userhash = get_userhash(conn, &username)?;
+    use redis::Commands;
+    let username: String = conn.get(names::user_username(&userhash))?;
 
-    let res: u32 = redis::Script::new(WITHDRAW_SCRIPT)
+    let script = redis::Script::new(WITHDRAW_SCRIPT);
+    let mut invocation = script.prepare_invoke();
+    invocation
         .key(names::user_balance(&userhash))
+        .key(names::user_history(&userhash))
+        .key(names::transaction_counter())
+        .key(names::transaction_table())
+        .key(names::ledger())
         .arg(amount)
-        .invoke(conn)?;
+        .arg(username)
+        .arg(actor)
+        .arg(&userhash)
+        .arg(BURN_ACCOUNT);
+    if !by_admin {
+        invocation.key(names::user_frozen(&userhash));
+    }
+
+    let res: u32 = invocation.invoke(conn)?;
     Ok(match res {
-        0 => true,
-        1 => false,
+        0 => TransactionStatus::Success,
+        1 => TransactionStatus::NotEnoughFunds,
+        2 => TransactionStatus::Frozen,
         _ => panic!("Invalid status code returned"),
     })
 }
+
+/// Why, when and by whom an account was frozen.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Freeze {
+    pub reason: String,
+    /// Admin who froze the account.
+    pub actor: String,
+    pub time: i64,
+}
+
+pub fn freeze_state(
+    conn: &mut redis::Connection,
+    username: &str,
+) -> redis::RedisResult<Option<Freeze>> {
+    let userhash = get_userhash(conn, username)?;
+
+    use redis::Commands;
+    let freeze: Option<String> = conn.get(names::user_frozen(&userhash))?;
+    Ok(
+        freeze.and_then(|freeze| match serde_json::from_str(&freeze) {
+            Ok(freeze) => Some(freeze),
+            Err(e) => {
+                /* Better to err on the side of keeping the account frozen. */
+                warn!("Malformed freeze on userhash {}: {}", userhash, e);
+                Some(Freeze {
+                    reason: String::new(),
+                    actor: String::new(),
+                    time: 0,
+                })
+            }
+        }),
+    )
+}
+
+/// Freezes the account, returning whether it wasn't frozen already.
+pub fn freeze(
+    conn: &mut redis::Connection,
+    username: &str,
+    freeze: &Freeze,
+) -> redis::RedisResult<bool> {
+    let userhash = get_userhash(conn, username)?;
+    let freeze = serde_json::to_string(freeze).expect("Freezes are always serializable");
+
+    use redis::Commands;
+    conn.set_nx(names::user_frozen(&userhash), freeze)
+}
+
+/// Lets the account move money again, returning whether it was frozen.
+pub fn unfreeze(conn: &mut redis::Connection, username: &str) -> redis::RedisResult<bool> {
+    let userhash = get_userhash(conn, username)?;
+
+    use redis::Commands;
+    let removed: u32 = conn.del(names::user_frozen(&userhash))?;
+    Ok(removed > 0)
+}
+
+/// Tests that need a Redis server to run against. They're ignored by default,
+/// and run with `cargo test -- --ignored` against the server and database in
+/// `JOAO_TEST_REDIS`, which they write to but never flush.
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Server tests connect to when `JOAO_TEST_REDIS` isn't set.
+    const DEFAULT_TEST_REDIS: &str = "redis://127.0.0.1:6380/15";
+
+    pub fn connect() -> redis::Connection {
+        let url =
+            std::env::var("JOAO_TEST_REDIS").unwrap_or_else(|_| DEFAULT_TEST_REDIS.to_owned());
+        redis::Client::open(url.as_str())
+            .and_then(|client| client.get_connection())
+            .unwrap_or_else(|e| panic!("Could not connect to {}: {}", url, e))
+    }
+
+    /// A username no other test run picks, so that tests don't step on each
+    /// other's accounts.
+    pub fn username(prefix: &str) -> String {
+        format!("{}{:08x}", prefix, rand::random::<u32>())
+    }
+
+    /// Opens an account with the given key, hashed as cheaply as it gets.
+    pub fn account(conn: &mut redis::Connection, username: &str, key: &str) {
+        use crate::keyhash::BCrypt;
+        let keyhash = BCrypt { cost: 4 }.generate(key).unwrap();
+        let result = create_account(
+            conn,
+            username.to_owned(),
+            format!("{}@example.com", username),
+            username.to_owned(),
+            keyhash,
+        )
+        .unwrap();
+        assert_eq!(result, "+OK", "Could not open an account for {}", username);
+    }
+
+    #[test]
+    #[ignore]
+    fn history_pages_past_the_end_start_at_the_newest() {
+        let mut conn = connect();
+        let username = username("history");
+        account(&mut conn, &username, "hunter2");
+        for amount in 1..=3 {
+            deposit(&mut conn, username.clone(), amount, SYSTEM_ACTOR).unwrap();
+        }
+
+        let (len, newest) = history(&mut conn, &username, None, 2).unwrap();
+        assert_eq!(len, 4);
+        assert_eq!(newest.len(), 2);
+        for below in &[4, 5, 6, 100] {
+            assert_eq!(
+                history(&mut conn, &username, Some(*below), 2).unwrap(),
+                (len, newest.clone())
+            );
+        }
+
+        let (_, oldest) = history(&mut conn, &username, Some(2), 10).unwrap();
+        let (_, all) = history(&mut conn, &username, None, 10).unwrap();
+        assert_eq!(oldest, all[2..].to_vec());
+        assert_eq!(
+            history(&mut conn, &username, Some(0), 2).unwrap(),
+            (len, vec![])
+        );
+    }
+
+    #[test]
+    #[ignore]
+    fn expired_sessions_are_turned_down() {
+        let mut conn = connect();
+        let username = username("expired");
+        account(&mut conn, &username, "hunter2");
+        let now = chrono::Utc::now().timestamp();
+        let session = |expires| Session {
+            nonce: String::new(),
+            expires,
+            created: now - 120,
+            last_used: now - 120,
+            ip: None,
+            user_agent: None,
+        };
+        create_session(&mut conn, &username, "fresh", &session(now + 60)).unwrap();
+        create_session(&mut conn, &username, "stale", &session(now - 60)).unwrap();
+
+        assert!(!touch_session(&mut conn, &username, "stale").unwrap());
+        assert!(touch_session(&mut conn, &username, "fresh").unwrap());
+        let listed = list_sessions(&mut conn, &username).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].0, "fresh");
+        assert!(listed[0].1.last_used >= now);
+    }
+
+    #[test]
+    #[ignore]
+    fn changing_the_key_revokes_api_keys() {
+        let mut conn = connect();
+        let username = username("rekeyed");
+        account(&mut conn, &username, "hunter2");
+        let key = ApiKey {
+            id: format!("{:016x}", rand::random::<u64>()),
+            name: "bot".to_owned(),
+            secret_hash: String::new(),
+            scopes: vec![ApiScope::ReadOnly],
+            created: 0,
+            expires: None,
+        };
+        create_api_key(&mut conn, &username, &key).unwrap();
+        assert!(find_api_key(&mut conn, &key.id).unwrap().is_some());
+
+        assert_eq!(
+            change_key(&mut conn, &username, String::new(), None).unwrap(),
+            1
+        );
+        assert!(find_api_key(&mut conn, &key.id).unwrap().is_none());
+        assert!(list_api_keys(&mut conn, &username).unwrap().is_empty());
+        use redis::Commands;
+        let filed: Option<String> = conn.hget(names::api_key_table(), &key.id).unwrap();
+        assert_eq!(filed, None);
+    }
+
+    #[test]
+    #[ignore]
+    fn old_usernames_get_folded() {
+        use redis::Commands;
+        let mut conn = connect();
+        let table = names::uid_table();
+
+        /* Filed as given, the way accounts were before usernames were folded. */
+        let username = username("Legacy");
+        account(&mut conn, &username, "hunter2");
+        let folded = naming::fold(&username);
+        let userhash: String = conn.hget(&table, &folded).unwrap();
+        conn.hdel::<_, _, ()>(&table, &folded).unwrap();
+        conn.hset::<_, _, _, ()>(&table, &username, &userhash)
+            .unwrap();
+
+        /* Another account whose username folds the same as this one's can't
+         * be told apart from it automatically. */
+        let clashing = username.to_uppercase();
+        conn.hset::<_, _, _, ()>(&table, &clashing, "elsewhere")
+            .unwrap();
+        conn.del::<_, ()>(names::uid_table_folded()).unwrap();
+        match fold_uid_table(&mut conn).unwrap() {
+            Err(clashes) => assert!(clashes.contains(&vec![clashing.clone(), username.clone()])),
+            Ok(refiled) => panic!("Refiled {} usernames despite the clash", refiled),
+        }
+        let filed: Option<String> = conn.hget(&table, &username).unwrap();
+        assert_eq!(filed.as_ref(), Some(&userhash));
+
+        conn.hdel::<_, _, ()>(&table, &clashing).unwrap();
+        assert!(fold_uid_table(&mut conn).unwrap().unwrap() >= 1);
+        let filed: Option<String> = conn.hget(&table, &username).unwrap();
+        assert_eq!(filed, None);
+        let filed: Option<String> = conn.hget(&table, &folded).unwrap();
+        assert_eq!(filed, Some(userhash));
+        assert_eq!(fold_uid_table(&mut conn).unwrap(), Ok(0));
+
+        /* Which is what keeps the other spellings from being registered. */
+        let result = create_account(
+            &mut conn,
+            folded,
+            "someone@example.com".to_owned(),
+            "Someone".to_owned(),
+            String::new(),
+        )
+        .unwrap();
+        assert_eq!(result, "-KeyExists");
+    }
+}
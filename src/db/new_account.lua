@@ -8,6 +8,11 @@
 --      KEYS[6] - user:balance
 --      KEYS[7] - uid_table
 --      KEYS[8] - user:username
+--      KEYS[9] - user:created
+--      KEYS[10] - ledger
+--      KEYS[11] - user:history
+--      KEYS[12] - transaction counter
+--      KEYS[13] - transaction table
 --
 -- 		ARGV[1] - Starting balance.
 --      ARGV[2] - User's email account.
@@ -16,9 +21,19 @@
 --      ARGV[5] - Salt value used for the keyhash.
 --      ARGV[6] - Username.
 --      ARGV[7] - Userhash.
+--      ARGV[8] - Username, folded as it's filed in the uid table.
+--      ARGV[9] - Current time.
+--      ARGV[10] - Account the starting balance is minted from.
+--      ARGV[11] - Who the starting balance is deposited by.
 --
 
-if redis.call("hexists", KEYS[7], ARGV[6]) == 1 then
+-- The clock is read and the ledger appended to, which only goes for
+-- replicating effects.
+redis.replicate_commands()
+
+-- Every username is filed folded, those from before usernames were folded
+-- included once the server has started up, so this catches any spelling.
+if redis.call("hexists", KEYS[7], ARGV[8]) == 1 then
 	return "-KeyExists"
 end
 
@@ -37,12 +52,39 @@ redis.call("set", KEYS[1], ARGV[3])
 redis.call("set", KEYS[3], ARGV[4])
 redis.call("set", KEYS[4], ARGV[5])
 redis.call("set", KEYS[8], ARGV[6])
+redis.call("set", KEYS[9], ARGV[9])
 
 if redis.call("get", KEYS[5]) then
 	redis.call("del", KEYS[5])
 end
 
-redis.call("hset", KEYS[7], ARGV[6], ARGV[7])
+redis.call("hset", KEYS[7], ARGV[8], ARGV[7])
+
+-- The starting balance opens the history, so that it explains the balance.
+local amount = tonumber(ARGV[1])
+local record = {}
+record.kind    = "deposit"
+record.account = ARGV[6]
+record.actor   = ARGV[11]
+record.amount  = amount
+record.balance = amount
+record.id      = tostring(redis.call("incr", KEYS[12]))
+record.time    = tonumber(redis.call("time")[1])
+local json_record = cjson.encode(record)
+
+redis.call("hset", KEYS[13], record.id, json_record)
+redis.call("lpush", KEYS[11], json_record)
+
+if amount > 0 then
+	local postings = {
+		{account = ARGV[10], amount = -amount},
+		{account = ARGV[7], amount = amount},
+	}
+	redis.call("xadd", KEYS[10], "*",
+		"kind", "open",
+		"transaction", record.id,
+		"postings", cjson.encode(postings))
+end
 
 return "+OK"
 
@@ -0,0 +1,36 @@
+--[[
+    refresh_session.lua: Rotates the refresh token of a session.
+
+    KEYS[1]: user tokens
+    ARGV[1]: session id
+    ARGV[2]: nonce of the refresh token being presented
+    ARGV[3]: nonce of the refresh token replacing it
+    ARGV[4]: new expiry date of the session
+    ARGV[5]: current time
+
+    Returns 0 on success, 1 if the session is gone and 2 if the presented
+    token had already been rotated out.
+]]
+
+local current = redis.call("hget", KEYS[1], ARGV[1])
+if not current then
+    return 1
+end
+
+local session = cjson.decode(current)
+if session.nonce ~= ARGV[2] then
+    -- Someone is replaying an old token, so we can't tell who is legit.
+    redis.call("hdel", KEYS[1], ARGV[1])
+    return 2
+end
+if session.expires < tonumber(ARGV[5]) then
+    redis.call("hdel", KEYS[1], ARGV[1])
+    return 1
+end
+
+session.nonce     = ARGV[3]
+session.expires   = tonumber(ARGV[4])
+session.last_used = tonumber(ARGV[5])
+redis.call("hset", KEYS[1], ARGV[1], cjson.encode(session))
+
+return 0
@@ -0,0 +1,28 @@
+--[[
+    touch_session.lua: Marks a session as used, if it is still around and has
+    not expired yet. An expired session is dropped on the spot.
+
+    KEYS[1]: user tokens
+    ARGV[1]: session id
+
+    Returns 1 if the session is still around and 0 otherwise.
+]]
+
+redis.replicate_commands()
+
+local current = redis.call("hget", KEYS[1], ARGV[1])
+if not current then
+    return 0
+end
+
+local now = tonumber(redis.call("time")[1])
+local session = cjson.decode(current)
+if not session.expires or session.expires < now then
+    redis.call("hdel", KEYS[1], ARGV[1])
+    return 0
+end
+
+session.last_used = now
+redis.call("hset", KEYS[1], ARGV[1], cjson.encode(session))
+
+return 1
@@ -8,10 +8,20 @@ local USER0_COOLDOWN  = KEYS[3]
 local USER1_BALANCE   = KEYS[4]
 local USER1_HISTORY   = KEYS[5]
 local USER1_COOLDOWN  = KEYS[6]
+local USER0_FROZEN    = KEYS[7]
+local USER1_FROZEN    = KEYS[8]
+local TX_COUNTER      = KEYS[9]
+local TX_TABLE        = KEYS[10]
+local LEDGER          = KEYS[11]
 
 local AMOUNT          = ARGV[1]
 local USER0_USERNAME  = ARGV[2]
 local USER1_USERNAME  = ARGV[3]
+local USER0_HASH      = ARGV[4]
+local USER1_HASH      = ARGV[5]
+
+-- We read the clock further down, so only the writes may get replicated.
+redis.replicate_commands()
 
 local srcValue  = redis.call("get", USER0_BALANCE)
 local destValue = redis.call("get", USER1_BALANCE)
@@ -23,6 +33,10 @@ if not destValue then
     return 3
 end
 
+if redis.call("exists", USER0_FROZEN) == 1 or redis.call("exists", USER1_FROZEN) == 1 then
+    return 5
+end
+
 if redis.call("get", USER0_COOLDOWN) or redis.call("get", USER1_COOLDOWN) then
 	return 4
 end
@@ -37,14 +51,28 @@ if amt <= srcBal then
 
 	-- Record the transaction for both of them.
 	local record = {}
+	record.kind    = "transfer"
 	record.from    = USER0_USERNAME
 	record.to      = USER1_USERNAME
 	record.amount  = amt
+	record.id      = tostring(redis.call("incr", TX_COUNTER))
+	record.time    = tonumber(redis.call("time")[1])
 	local json_record = cjson.encode(record)
 
+	redis.call("hset", TX_TABLE, record.id, json_record)
 	redis.call("lpush", USER0_HISTORY, json_record)
 	redis.call("lpush", USER1_HISTORY, json_record)
 
+	-- Journal it, as postings adding up to nothing.
+	local postings = {
+		{account = USER0_HASH, amount = -amt},
+		{account = USER1_HASH, amount = amt},
+	}
+	redis.call("xadd", LEDGER, "*",
+		"kind", "transfer",
+		"transaction", record.id,
+		"postings", cjson.encode(postings))
+
 	-- Activate cooldown for both of them
 	redis.call("set", USER0_COOLDOWN, "1")
 	redis.call("set", USER1_COOLDOWN, "1")
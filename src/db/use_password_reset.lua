@@ -0,0 +1,22 @@
+--[[
+    use_password_reset.lua: Uses up a key reset token.
+
+    KEYS[1]: password reset
+    KEYS[2]: user reset
+    ARGV[1]: digest of the token
+
+    Returns 1 if the token was good and 0 if it is gone or was replaced by a
+    newer one.
+]]
+
+if redis.call("exists", KEYS[1]) == 0 then
+    return 0
+end
+redis.call("del", KEYS[1])
+
+if redis.call("get", KEYS[2]) ~= ARGV[1] then
+    return 0
+end
+redis.call("del", KEYS[2])
+
+return 1
@@ -0,0 +1,17 @@
+--[[
+    use_totp_step.lua: Marks a TOTP step as used, so that its code can't be
+    used again.
+
+    KEYS[1]: user totp
+    ARGV[1]: step
+
+    Returns 1 if the step is newer than the last one used, 0 otherwise.
+]]
+
+local last = tonumber(redis.call("hget", KEYS[1], "last_step") or "0")
+if tonumber(ARGV[1]) <= last then
+    return 0
+end
+
+redis.call("hset", KEYS[1], "last_step", ARGV[1])
+return 1
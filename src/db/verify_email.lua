@@ -0,0 +1,23 @@
+--[[
+    verify_email.lua: Uses up an email verification token.
+
+    KEYS[1]: email verification
+    KEYS[2]: user email
+    KEYS[3]: user email verified
+    ARGV[1]: email address the token was issued for
+
+    Returns 1 if the address got verified and 0 if the token is gone or the
+    user has since moved on to another address.
+]]
+
+if redis.call("exists", KEYS[1]) == 0 then
+    return 0
+end
+redis.call("del", KEYS[1])
+
+if redis.call("get", KEYS[2]) ~= ARGV[1] then
+    return 0
+end
+redis.call("set", KEYS[3], "1")
+
+return 1
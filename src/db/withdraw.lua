@@ -1,13 +1,56 @@
 --[[
+    withdraw.lua: Takes money out of an account, recording it in its history.
+
     KEYS[1]: user balance
+    KEYS[2]: user history
+    KEYS[3]: transaction counter
+    KEYS[4]: transaction table
+    KEYS[5]: ledger
+    KEYS[6]: user frozen (optional, left out for admins)
     ARGV[1]: amount to withdraw
+    ARGV[2]: username
+    ARGV[3]: who made the withdrawal
+    ARGV[4]: userhash
+    ARGV[5]: account the money is burnt into
+
+    Returns 0 on success, 1 if there aren't enough funds and 2 if the account
+    is frozen.
 ]]
 
+-- We read the clock further down, so only the writes may get replicated.
+redis.replicate_commands()
+
+if KEYS[6] and redis.call("exists", KEYS[6]) == 1 then
+    return 2
+end
+
 local amt = tonumber(ARGV[1])
 local value = tonumber(redis.call("get", KEYS[1]))
 
 if amt <= value then
-    redis.call("decrby", KEYS[1], ARGV[1])
+    local balance = redis.call("decrby", KEYS[1], ARGV[1])
+
+    local record = {}
+    record.kind    = "withdrawal"
+    record.account = ARGV[2]
+    record.actor   = ARGV[3]
+    record.amount  = amt
+    record.balance = balance
+    record.id      = tostring(redis.call("incr", KEYS[3]))
+    record.time    = tonumber(redis.call("time")[1])
+    local json_record = cjson.encode(record)
+
+    redis.call("hset", KEYS[4], record.id, json_record)
+    redis.call("lpush", KEYS[2], json_record)
+
+    local postings = {
+        {account = ARGV[4], amount = -amt},
+        {account = ARGV[5], amount = amt},
+    }
+    redis.call("xadd", KEYS[5], "*",
+        "kind", "withdrawal",
+        "transaction", record.id,
+        "postings", cjson.encode(postings))
     return 0
 else 
     return 1
@@ -1,49 +1,286 @@
-pub fn generate(key: String) -> (String, String) {
-    /* BCrypt only allows for keys with a maximum of 72 bytes. */
-    let pass = key.into_bytes().into_iter().take(72).collect::<Vec<_>>();
-    /*	let salt = (0..16)
-        .into_iter()
-        .map(|_| rand::random::<u8>())
-        .collect::<Vec<_>>();
+//! Generation and verification of keyhashes.
+//!
+//! Keyhashes are self-describing: the algorithm, its parameters and the salt
+//! all live in the string along with the hash, in BCrypt's modular crypt format
+//! ("$2y$12$...") or in the PHC string format for Argon2id
+//! ("$argon2id$v=19$m=19456,t=2,p=1$..."). This lets us verify keys against
+//! whatever they were hashed with, regardless of what we currently hash with.
+use crate::settings::{HashAlgorithm, KeyHash};
 
-    let mut out = [0_u8; 24];
-    bcrypt::bcrypt(bcrypt::DEFAULT_COST / 2, &salt[..], &pass[..], &mut out);
+/// BCrypt only ever looks at this many bytes of a key.
+const BCRYPT_MAX_KEY_SIZE: usize = 72;
+/// Digits BCrypt encodes its salts and hashes with, in order.
+const BCRYPT_ALPHABET: &'static [u8] =
+    b"./ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+/// Size of the salt generated for Argon2id.
+const ARGON2_SALT_SIZE: usize = 16;
 
-    let pass = out.iter()
-        .map(|val| format!("{:02x}", val))
-        .collect::<String>();
-    let salt = salt.iter()
-        .map(|val| format!("{:02x}", val))
-        .collect::<String>();*/
-
-    let pass = bcrypt::hash(pass, bcrypt::DEFAULT_COST / 2).expect("oh god oh fuck");
-
-    (pass, "".to_owned())
+#[derive(Debug)]
+pub enum GenerateError {
+    /// The key is longer than the hasher would actually look at.
+    KeyTooLong,
+    /// The hasher didn't like its parameters.
+    Failed(String),
 }
 
+#[derive(Debug)]
 pub enum VerifyError {
+    /// The keyhash is not in a format of any of the supported algorithms.
+    UnknownAlgorithm,
+    /// The keyhash claims to be of a supported algorithm, but is malformed.
     InvalidHash,
-    InvalidSalt,
 }
 
-fn _from_hex(val: &str) -> Result<Vec<u8>, ()> {
-    let chars = val.chars().collect::<Vec<_>>();
+pub trait PasswordHasher: Send + Sync {
+    /// Hashes the key under freshly generated salt.
+    fn generate(&self, key: &str) -> Result<String, GenerateError>;
+
+    /// Whether the keyhash was generated by this hasher with its current
+    /// parameters, as opposed to needing to be generated again.
+    fn is_current(&self, hash: &str) -> bool;
+}
+
+/// Builds the hasher described by the settings.
+pub fn hasher(settings: &KeyHash) -> Box<dyn PasswordHasher> {
+    match settings.algorithm {
+        HashAlgorithm::BCrypt => Box::new(BCrypt {
+            cost: settings.cost,
+        }),
+        HashAlgorithm::Argon2id => Box::new(Argon2id {
+            memory: settings.memory,
+            iterations: settings.iterations,
+            parallelism: settings.parallelism,
+        }),
+    }
+}
+
+pub struct BCrypt {
+    pub cost: u32,
+}
+impl PasswordHasher for BCrypt {
+    fn generate(&self, key: &str) -> Result<String, GenerateError> {
+        if key.len() > BCRYPT_MAX_KEY_SIZE {
+            return Err(GenerateError::KeyTooLong);
+        }
+
+        bcrypt::hash(key, self.cost).map_err(|what| GenerateError::Failed(format!("{}", what)))
+    }
+
+    fn is_current(&self, hash: &str) -> bool {
+        /* BCrypt hashes look like "$2y$06$<salt><hash>". */
+        is_bcrypt(hash)
+            && hash
+                .split('$')
+                .nth(2)
+                .and_then(|field| field.parse::<u32>().ok())
+                .map(|cost| cost == self.cost)
+                .unwrap_or(false)
+    }
+}
+
+pub struct Argon2id {
+    /// Memory cost, in KiB.
+    pub memory: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+impl PasswordHasher for Argon2id {
+    fn generate(&self, key: &str) -> Result<String, GenerateError> {
+        let salt = (0..ARGON2_SALT_SIZE)
+            .map(|_| rand::random::<u8>())
+            .collect::<Vec<_>>();
+        let config = argon2::Config {
+            variant: argon2::Variant::Argon2id,
+            version: argon2::Version::Version13,
+            mem_cost: self.memory,
+            time_cost: self.iterations,
+            lanes: self.parallelism,
+            ..Default::default()
+        };
+
+        argon2::hash_encoded(key.as_bytes(), &salt[..], &config)
+            .map_err(|what| GenerateError::Failed(format!("{}", what)))
+    }
+
+    fn is_current(&self, hash: &str) -> bool {
+        let params = format!(
+            "$argon2id$v=19$m={},t={},p={}$",
+            self.memory, self.iterations, self.parallelism
+        );
+        hash.starts_with(&params)
+    }
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    hash.starts_with("$2")
+}
+
+/// Whether the BCrypt keyhash is well formed. The bcrypt crate panics on some
+/// malformed keyhashes rather than failing, so it can't be trusted to check.
+fn is_well_formed_bcrypt(hash: &str) -> bool {
+    let fields = hash.split('$').collect::<Vec<_>>();
+    if fields.len() != 4 || !fields[0].is_empty() {
+        return false;
+    }
+
+    let prefix = ["2a", "2b", "2y"].contains(&fields[1]);
+    let cost = fields[2].len() == 2
+        && fields[2]
+            .parse::<u32>()
+            .map(|cost| cost >= 4 && cost <= 31)
+            .unwrap_or(false);
 
-    chars[..]
-        .chunks(2)
-        .map(|chars| {
-            let mut s = String::new();
-            for c in chars {
-                s.push(*c);
-            }
+    /* 22 digits of salt followed by 31 digits of hash. */
+    let digits = fields[3]
+        .bytes()
+        .map(|c| BCRYPT_ALPHABET.iter().position(|&digit| digit == c))
+        .collect::<Option<Vec<_>>>();
+    let digits = match digits {
+        Some(ref digits) if digits.len() == 53 => digits,
+        _ => return false,
+    };
 
-            u8::from_str_radix(s.as_str(), 16).map_err(|_| ())
-        })
-        .collect()
+    /* The last digits of both carry padding bits, which must be zeroed. */
+    prefix && cost && digits[21] % 16 == 0 && digits[52] % 4 == 0
 }
 
-pub fn verify(key: String, hash: String, _salt: String) -> Result<bool, VerifyError> {
-    let pass = key.into_bytes().into_iter().take(72).collect::<Vec<_>>();
-    Ok(bcrypt::verify(&pass[..], hash.as_str())
-        .expect("ALRIGHT WE FUCKED UP WITH BCRYPT PLS HELP ;-;"))
+fn is_argon2(hash: &str) -> bool {
+    hash.starts_with("$argon2")
+}
+
+/// Verifies the key against a keyhash generated by any of the supported
+/// algorithms, with any parameters.
+pub fn verify(key: &str, hash: &str) -> Result<bool, VerifyError> {
+    if is_bcrypt(hash) {
+        if !is_well_formed_bcrypt(hash) {
+            return Err(VerifyError::InvalidHash);
+        }
+
+        /* Older keyhashes were generated from keys silently cut short. */
+        let key = &key.as_bytes()[..key.len().min(BCRYPT_MAX_KEY_SIZE)];
+        bcrypt::verify(key, hash).map_err(|_| VerifyError::InvalidHash)
+    } else if is_argon2(hash) {
+        argon2::verify_encoded(hash, key.as_bytes()).map_err(|_| VerifyError::InvalidHash)
+    } else {
+        Err(VerifyError::UnknownAlgorithm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheapest hasher of each kind, so the tests don't take all day.
+    fn bcrypt_hash(key: &str) -> String {
+        BCrypt { cost: 4 }.generate(key).unwrap()
+    }
+
+    fn argon2_hash(key: &str) -> String {
+        Argon2id {
+            memory: 8,
+            iterations: 1,
+            parallelism: 1,
+        }
+        .generate(key)
+        .unwrap()
+    }
+
+    /// Swaps the character at the given index for another.
+    fn replace_at(hash: &str, index: usize, with: char) -> String {
+        let mut hash = hash.chars().collect::<Vec<_>>();
+        hash[index] = with;
+        hash.into_iter().collect()
+    }
+
+    fn assert_invalid(hash: &str) {
+        match verify("hunter2", hash) {
+            Err(VerifyError::InvalidHash) => {}
+            other => panic!("{:?} verified as {:?}", hash, other),
+        }
+    }
+
+    fn assert_unknown(hash: &str) {
+        match verify("hunter2", hash) {
+            Err(VerifyError::UnknownAlgorithm) => {}
+            other => panic!("{:?} verified as {:?}", hash, other),
+        }
+    }
+
+    #[test]
+    fn verifies_well_formed_hashes() {
+        let bcrypt = bcrypt_hash("hunter2");
+        assert!(is_well_formed_bcrypt(&bcrypt));
+        assert!(verify("hunter2", &bcrypt).unwrap());
+        assert!(!verify("hunter3", &bcrypt).unwrap());
+
+        let argon2 = argon2_hash("hunter2");
+        assert!(verify("hunter2", &argon2).unwrap());
+        assert!(!verify("hunter3", &argon2).unwrap());
+    }
+
+    #[test]
+    fn rejects_truncated_bcrypt() {
+        let hash = bcrypt_hash("hunter2");
+        for size in &[hash.len() - 1, hash.len() - 31, 29, 7, 4] {
+            assert!(!is_well_formed_bcrypt(&hash[..*size]));
+            assert_invalid(&hash[..*size]);
+        }
+    }
+
+    #[test]
+    fn rejects_bcrypt_cost_out_of_range() {
+        let hash = bcrypt_hash("hunter2");
+        for cost in &["03", "32", "99", "4", "004", "xx"] {
+            let hash = format!("$2y${}${}", cost, &hash[7..]);
+            assert!(!is_well_formed_bcrypt(&hash));
+            assert_invalid(&hash);
+        }
+    }
+
+    #[test]
+    fn rejects_bcrypt_outside_alphabet() {
+        let hash = bcrypt_hash("hunter2");
+        for digit in &['+', '=', '!', '$', 'é'] {
+            let hash = replace_at(&hash, 10, *digit);
+            assert!(!is_well_formed_bcrypt(&hash));
+            assert_invalid(&hash);
+        }
+    }
+
+    #[test]
+    fn rejects_bcrypt_padding_bits() {
+        /* "/" is the digit for one, which sets the lowest padding bit. */
+        let hash = bcrypt_hash("hunter2");
+        for index in &[7 + 21, 7 + 52] {
+            let hash = replace_at(&hash, *index, '/');
+            assert!(!is_well_formed_bcrypt(&hash));
+            assert_invalid(&hash);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_prefixes() {
+        let hash = bcrypt_hash("hunter2");
+        assert_invalid(&format!("$2x{}", &hash[3..]));
+        assert_invalid(&format!("$2{}", &hash[3..]));
+        assert_unknown(&format!("$5{}", &hash[3..]));
+        assert_unknown("$1$salt$hash");
+        assert_unknown("plaintext");
+    }
+
+    #[test]
+    fn rejects_malformed_argon2() {
+        let hash = argon2_hash("hunter2");
+        assert_invalid(&hash[..hash.len() - 10]);
+        assert_invalid(&hash.replace("m=8", "m=x"));
+        assert_invalid("$argon2id$v=19$m=8,t=1,p=1$garbage");
+        assert_invalid("$argon2id$");
+        assert_invalid("$argon2");
+    }
+
+    #[test]
+    fn rejects_empty_hash() {
+        assert_unknown("");
+        assert!(!is_well_formed_bcrypt(""));
+    }
 }
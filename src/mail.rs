@@ -0,0 +1,365 @@
+//! Delivery of mail to users.
+//!
+//! Mail is either handed over to a relay in plain SMTP, which is expected to
+//! take care of getting it anywhere past that, or written to an outbox
+//! directory, one file per message, for when there's nowhere to send it to.
+//!
+//! Nothing sent to the relay is encrypted, credentials included, so those are
+//! only sent to relays on the same machine unless explicitly allowed not to.
+use crate::settings::{Mail, MailTransport};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, TcpStream};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// How long we wait on the relay before giving up on it.
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub struct Message {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Debug)]
+pub enum MailError {
+    Io(io::Error),
+    /// The relay answered a command with something other than what we
+    /// expected of it.
+    Rejected(String),
+    /// Logging in to the relay would give the credentials away to anyone
+    /// between us and it.
+    Insecure(String),
+}
+impl From<io::Error> for MailError {
+    fn from(what: io::Error) -> MailError {
+        MailError::Io(what)
+    }
+}
+impl std::fmt::Display for MailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MailError::Io(what) => write!(f, "{}", what),
+            MailError::Rejected(reply) => write!(f, "rejected by relay: {}", reply),
+            MailError::Insecure(relay) => write!(
+                f,
+                "refusing to send credentials to {} over an unencrypted connection",
+                relay
+            ),
+        }
+    }
+}
+
+pub trait Mailer: Send + Sync {
+    fn send(&self, message: &Message) -> Result<(), MailError>;
+}
+
+/// Builds the mailer described by the settings.
+pub fn mailer(settings: &Mail) -> Box<dyn Mailer> {
+    match settings.transport {
+        MailTransport::Smtp => Box::new(Smtp {
+            address: settings.smtp_address.clone(),
+            from: settings.from.clone(),
+            credentials: match (&settings.smtp_username, &settings.smtp_password) {
+                (Some(username), Some(password)) => Some((username.clone(), password.clone())),
+                _ => None,
+            },
+            insecure: settings.smtp_insecure,
+        }),
+        MailTransport::Outbox => Box::new(Outbox {
+            directory: settings.outbox_directory.clone(),
+            from: settings.from.clone(),
+        }),
+    }
+}
+
+/// Lays the message out the way it goes over the wire, headers and all.
+fn render(from: &str, message: &Message) -> String {
+    let mut text = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\n\
+         MIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\
+         Content-Transfer-Encoding: 8bit\r\n\r\n",
+        from,
+        message.to,
+        message.subject,
+        chrono::Utc::now().to_rfc2822()
+    );
+    for line in message.body.lines() {
+        text.push_str(line);
+        text.push_str("\r\n");
+    }
+    text
+}
+
+pub struct Smtp {
+    pub address: String,
+    pub from: String,
+    pub credentials: Option<(String, String)>,
+    /// Whether the credentials may go to relays on other machines.
+    pub insecure: bool,
+}
+impl Smtp {
+    /// Whether the credentials may be sent to the relay at that address.
+    fn may_log_in(&self, relay: IpAddr) -> bool {
+        self.insecure || relay.is_loopback()
+    }
+
+    /// Reads a reply off the relay, which may span multiple lines, failing
+    /// unless its code is one of those expected.
+    fn expect<R: BufRead>(reader: &mut R, codes: &[u32]) -> Result<(), MailError> {
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(MailError::Rejected("connection closed".to_owned()));
+            }
+
+            /* Every line but the last one has a dash after the code. */
+            let line = line.trim_end();
+            if line.as_bytes().get(3) == Some(&b'-') {
+                continue;
+            }
+            let code = line
+                .get(..3)
+                .and_then(|code| code.parse::<u32>().ok())
+                .ok_or_else(|| MailError::Rejected(line.to_owned()))?;
+            return if codes.contains(&code) {
+                Ok(())
+            } else {
+                Err(MailError::Rejected(line.to_owned()))
+            };
+        }
+    }
+
+    fn command<R: BufRead, W: Write>(
+        reader: &mut R,
+        writer: &mut W,
+        command: &str,
+        codes: &[u32],
+    ) -> Result<(), MailError> {
+        writer.write_all(command.as_bytes())?;
+        writer.write_all(b"\r\n")?;
+        writer.flush()?;
+        Self::expect(reader, codes)
+    }
+}
+impl Mailer for Smtp {
+    fn send(&self, message: &Message) -> Result<(), MailError> {
+        let mut writer = TcpStream::connect(&self.address)?;
+        writer.set_read_timeout(Some(SMTP_TIMEOUT))?;
+        writer.set_write_timeout(Some(SMTP_TIMEOUT))?;
+        let mut reader = BufReader::new(writer.try_clone()?);
+
+        let domain = self.from.rsplit('@').next().unwrap_or("localhost");
+        Self::expect(&mut reader, &[220])?;
+        Self::command(
+            &mut reader,
+            &mut writer,
+            &format!("EHLO {}", domain),
+            &[250],
+        )?;
+        if let Some((ref username, ref password)) = self.credentials {
+            if !self.may_log_in(writer.peer_addr()?.ip()) {
+                return Err(MailError::Insecure(self.address.clone()));
+            }
+            let plain = base64::encode(&format!("\0{}\0{}", username, password));
+            Self::command(
+                &mut reader,
+                &mut writer,
+                &format!("AUTH PLAIN {}", plain),
+                &[235],
+            )?;
+        }
+        Self::command(
+            &mut reader,
+            &mut writer,
+            &format!("MAIL FROM:<{}>", self.from),
+            &[250],
+        )?;
+        Self::command(
+            &mut reader,
+            &mut writer,
+            &format!("RCPT TO:<{}>", message.to),
+            &[250, 251],
+        )?;
+        Self::command(&mut reader, &mut writer, "DATA", &[354])?;
+
+        /* Lines starting with a dot get another one, lest they end the data. */
+        let mut data = String::new();
+        for line in render(&self.from, message).lines() {
+            if line.starts_with('.') {
+                data.push('.');
+            }
+            data.push_str(line);
+            data.push_str("\r\n");
+        }
+        data.push('.');
+        Self::command(&mut reader, &mut writer, &data, &[250])?;
+
+        /* The message is in their hands by now, whatever they make of this. */
+        let _ = Self::command(&mut reader, &mut writer, "QUIT", &[221]);
+        Ok(())
+    }
+}
+
+pub struct Outbox {
+    pub directory: PathBuf,
+    pub from: String,
+}
+impl Mailer for Outbox {
+    fn send(&self, message: &Message) -> Result<(), MailError> {
+        std::fs::create_dir_all(&self.directory)?;
+
+        let name = format!(
+            "{}-{:016x}.eml",
+            chrono::Utc::now().format("%Y%m%d%H%M%S%.f"),
+            rand::random::<u64>()
+        );
+        std::fs::write(self.directory.join(name), render(&self.from, message))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn message() -> Message {
+        Message {
+            to: "alice@example.com".to_owned(),
+            subject: "Verify your email".to_owned(),
+            body: "Hello,\n.click the link.\n".to_owned(),
+        }
+    }
+
+    /// Plays a relay taking a single message, handing back every line it got.
+    fn relay() -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let relay = thread::spawn(move || {
+            let (mut writer, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(writer.try_clone().unwrap());
+            let mut lines = Vec::new();
+            let mut data = false;
+            writer.write_all(b"220 relay ready\r\n").unwrap();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    return lines;
+                }
+                let line = line.trim_end().to_owned();
+                let reply: &[u8] = if data {
+                    data = line != ".";
+                    if data {
+                        b""
+                    } else {
+                        b"250 queued\r\n"
+                    }
+                } else if line.starts_with("EHLO") {
+                    b"250-relay\r\n250 AUTH PLAIN\r\n"
+                } else if line.starts_with("AUTH") {
+                    b"235 welcome\r\n"
+                } else if line == "DATA" {
+                    data = true;
+                    b"354 go ahead\r\n"
+                } else if line == "QUIT" {
+                    b"221 bye\r\n"
+                } else {
+                    b"250 ok\r\n"
+                };
+                writer.write_all(reply).unwrap();
+                lines.push(line);
+            }
+        });
+        (address, relay)
+    }
+
+    fn smtp(address: String, credentials: bool, insecure: bool) -> Smtp {
+        Smtp {
+            address,
+            from: "joao@example.com".to_owned(),
+            credentials: if credentials {
+                Some(("joao".to_owned(), "hunter2".to_owned()))
+            } else {
+                None
+            },
+            insecure,
+        }
+    }
+
+    #[test]
+    fn smtp_hands_the_message_over() {
+        let (address, relay) = relay();
+        smtp(address, true, false).send(&message()).unwrap();
+        let lines = relay.join().unwrap();
+
+        assert_eq!(lines[0], "EHLO example.com");
+        assert_eq!(
+            lines[1],
+            format!("AUTH PLAIN {}", base64::encode("\0joao\0hunter2"))
+        );
+        assert_eq!(lines[2], "MAIL FROM:<joao@example.com>");
+        assert_eq!(lines[3], "RCPT TO:<alice@example.com>");
+        assert_eq!(lines[4], "DATA");
+        assert!(lines.contains(&"To: alice@example.com".to_owned()));
+        /* Lines starting with a dot get another one. */
+        assert!(lines.contains(&"..click the link.".to_owned()));
+        assert_eq!(lines[lines.len() - 2], ".");
+        assert_eq!(lines[lines.len() - 1], "QUIT");
+    }
+
+    #[test]
+    fn smtp_keeps_credentials_to_local_relays() {
+        let local = smtp("127.0.0.1:25".to_owned(), true, false);
+        assert!(local.may_log_in("127.0.0.1".parse().unwrap()));
+        assert!(local.may_log_in("::1".parse().unwrap()));
+        assert!(!local.may_log_in("192.0.2.1".parse().unwrap()));
+        assert!(!local.may_log_in("2001:db8::1".parse().unwrap()));
+
+        let insecure = smtp("192.0.2.1:25".to_owned(), true, true);
+        assert!(insecure.may_log_in("192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn outbox_writes_one_message_per_file() {
+        let directory =
+            std::env::temp_dir().join(format!("joao-outbox-{:016x}", rand::random::<u64>()));
+        let outbox = Outbox {
+            directory: directory.join("nested"),
+            from: "joao@example.com".to_owned(),
+        };
+        let message = Message {
+            to: "alice@example.com".to_owned(),
+            subject: "Verify your email".to_owned(),
+            body: "Hello,\nclick the link.\n".to_owned(),
+        };
+        outbox.send(&message).unwrap();
+        outbox.send(&message).unwrap();
+
+        let mut files = std::fs::read_dir(&outbox.directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files.len(), 2);
+        for file in &files {
+            assert_eq!(file.extension().and_then(|ext| ext.to_str()), Some("eml"));
+        }
+
+        let text = std::fs::read_to_string(&files[0]).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let mut parts = text.splitn(2, "\r\n\r\n");
+        let (headers, body) = (parts.next().unwrap(), parts.next().unwrap());
+        let headers = headers.split("\r\n").collect::<Vec<_>>();
+        assert_eq!(headers[0], "From: joao@example.com");
+        assert_eq!(headers[1], "To: alice@example.com");
+        assert_eq!(headers[2], "Subject: Verify your email");
+        let date = headers[3].trim_start_matches("Date: ");
+        assert!(chrono::DateTime::parse_from_rfc2822(date).is_ok());
+        assert!(headers.contains(&"Content-Type: text/plain; charset=utf-8"));
+        assert_eq!(body, "Hello,\r\nclick the link.\r\n");
+    }
+}
@@ -15,7 +15,6 @@ extern crate rocket_contrib;
 extern crate lazy_static;
 
 extern crate bcrypt;
-extern crate jsonwebtoken as jwt;
 
 const PKG_NAME: &'static str = env!("CARGO_PKG_NAME");
 const PKG_VERSION: &'static str = env!("CARGO_PKG_VERSION");
@@ -25,14 +24,24 @@ mod api;
 mod db;
 mod keyhash;
 mod logger;
+mod mail;
+mod naming;
 mod pool;
 mod settings;
+mod signing;
 mod state;
+mod totp;
 
 fn main() {
     let args = cmdargs::parse();
     let settings = init_settings(&args);
+    if let cmdargs::Command::Check = args.command {
+        std::process::exit(check(&settings, &args));
+    }
+
     let fslogger = init_logger(&settings);
+    let keys = init_keys(&settings);
+    fold_usernames(&settings);
 
     trace!("We have a logger!");
     info!("{} - {}", PKG_NAME, PKG_TITLE);
@@ -87,6 +96,9 @@ fn main() {
                     .get_connection()
                     .expect("Could not acquire database connection")
             }),
+            hasher: keyhash::hasher(&settings.key_hash),
+            keys,
+            mailer: mail::mailer(&settings.mail),
             settings: settings,
         }
     })
@@ -178,22 +190,173 @@ fn init_settings(args: &cmdargs::Arguments) -> settings::Settings {
         None => "".to_owned(),
     };
 
-    toml::from_str::<settings::Settings>(&data).unwrap_or_else(|what| {
+    let settings = toml::from_str::<settings::Settings>(&data).unwrap_or_else(|what| {
         eprintln!("Cannot parse config:");
         eprintln!("{:#?}", what);
         std::process::exit(1)
-    })
+    });
+
+    /* Better find out the hasher doesn't like its parameters right now. */
+    if let Err(what) = keyhash::hasher(&settings.key_hash).generate(PKG_NAME) {
+        eprintln!("Invalid keyhash settings:");
+        eprintln!("{:#?}", what);
+        std::process::exit(1);
+    }
+
+    settings
+}
+
+/// Looks through the database for inconsistencies, repairing the safe ones if
+/// asked to, and reports them. Exits with 2 if any are left unrepaired.
+fn check(settings: &settings::Settings, args: &cmdargs::Arguments) -> i32 {
+    let url = format!(
+        "redis://{}/{}",
+        settings.database_address, settings.database_id
+    );
+    let mut conn = match redis::Client::open(url.as_str()).and_then(|c| c.get_connection()) {
+        Ok(conn) => conn,
+        Err(what) => {
+            eprintln!("Cannot connect to the database at {}:", url);
+            eprintln!("{}", what);
+            return 1;
+        }
+    };
+
+    let findings = match db::check::check(&mut conn) {
+        Ok(findings) => findings,
+        Err(what) => {
+            eprintln!("Cannot check the database:");
+            eprintln!("{}", what);
+            return 1;
+        }
+    };
+
+    let mut report = Vec::with_capacity(findings.len());
+    for finding in findings {
+        let repaired = args.repair
+            && db::check::repair(&mut conn, &finding).unwrap_or_else(|what| {
+                eprintln!("Cannot repair that {}: {}", finding, what);
+                false
+            });
+        report.push((finding, repaired));
+    }
+    let remaining = report.iter().filter(|(_, repaired)| !repaired).count();
+
+    if args.json {
+        let findings = report
+            .iter()
+            .map(|(finding, repaired)| {
+                json!({
+                    "finding": finding,
+                    "repairable": finding.repairable(),
+                    "repaired": repaired,
+                })
+            })
+            .collect::<Vec<_>>();
+        let report = json!({
+            "findings": findings,
+            "remaining": remaining,
+        });
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).expect("Reports can always be serialized")
+        );
+    } else {
+        for (finding, repaired) in &report {
+            let note = if *repaired {
+                " (repaired)"
+            } else if finding.repairable() {
+                " (repairable)"
+            } else {
+                ""
+            };
+            println!("{}{}", finding, note);
+        }
+        println!(
+            "{} inconsistencies found, {} repaired",
+            report.len(),
+            report.len() - remaining
+        );
+    }
+
+    if remaining == 0 {
+        0
+    } else {
+        2
+    }
+}
+
+/// Files the usernames of accounts from before usernames were folded the way
+/// every other is filed, which has to be done before anyone can register.
+fn fold_usernames(settings: &settings::Settings) {
+    let url = format!(
+        "redis://{}/{}",
+        settings.database_address, settings.database_id
+    );
+    let folded = redis::Client::open(url.as_str())
+        .and_then(|client| client.get_connection())
+        .and_then(|mut conn| db::fold_uid_table(&mut conn));
+    match folded {
+        Ok(Ok(0)) => {}
+        Ok(Ok(refiled)) => info!("Filed {} usernames under their folded spelling", refiled),
+        Ok(Err(clashes)) => {
+            error!("Usernames of different accounts fold alike, and have to be told apart:");
+            for usernames in clashes {
+                error!("{}", usernames.join(", "));
+            }
+            std::process::exit(1);
+        }
+        Err(what) => {
+            error!("Cannot fold the usernames in the database at {}:", url);
+            error!("{}", what);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn init_keys(settings: &settings::Settings) -> signing::Keys {
+    match signing::Keys::load(&settings.auth) {
+        Ok((keys, random)) => {
+            if random {
+                warn!("No secret was given to sign tokens with, so a random one was made up");
+                warn!("Tokens will not outlive this process, nor be shared with replicas");
+            }
+            keys
+        }
+        Err(what) => {
+            error!("Cannot load the keys to sign tokens with:");
+            error!("{:#?}", what);
+            std::process::exit(1);
+        }
+    }
 }
 
 mod cmdargs {
     pub struct Arguments {
         pub config: Option<String>,
+        pub command: Command,
+        /// Whether the report of `check` is written out in JSON.
+        pub json: bool,
+        /// Whether `check` repairs what it safely can.
+        pub repair: bool,
     }
     impl Default for Arguments {
         fn default() -> Arguments {
-            Arguments { config: None }
+            Arguments {
+                config: None,
+                command: Command::Serve,
+                json: false,
+                repair: false,
+            }
         }
     }
+
+    pub enum Command {
+        /// Serves the API, which is what happens unless told otherwise.
+        Serve,
+        /// Checks the database for inconsistencies.
+        Check,
+    }
     use core::iter::FromIterator;
     impl FromIterator<Bits> for Arguments {
         fn from_iter<T: IntoIterator<Item = Bits>>(iter: T) -> Arguments {
@@ -203,6 +366,9 @@ mod cmdargs {
             while let Some(bit) = iter.next() {
                 match bit {
                     Bits::Config(config) => args.config = Some(config),
+                    Bits::Command(command) => args.command = command,
+                    Bits::Json => args.json = true,
+                    Bits::Repair => args.repair = true,
                 }
             }
 
@@ -212,6 +378,9 @@ mod cmdargs {
 
     pub enum Bits {
         Config(String),
+        Command(Command),
+        Json,
+        Repair,
     }
 
     pub struct Parser<I: Iterator<Item = String>>(pub I);
@@ -233,21 +402,36 @@ mod cmdargs {
                         Err(format!(r#"expected path to config file after "{}""#, s))
                     }
                 }
+                "check" => Ok(Bits::Command(Command::Check)),
+                "--json" => Ok(Bits::Json),
+                "--repair" => Ok(Bits::Repair),
                 _ => Err(format!("unknown command line parameter: {}", bit)),
             })
         }
     }
 
+    fn usage(what: &str) -> ! {
+        eprintln!("{}", what);
+        eprintln!(
+            "Usage: {} [-c <config file>] [check [--json] [--repair]]",
+            crate::PKG_NAME
+        );
+        std::process::exit(1)
+    }
+
     pub fn parse() -> Arguments {
-        Parser(std::env::args().skip(1))
+        let args = Parser(std::env::args().skip(1))
             .map(|parsed| match parsed {
                 Ok(bit) => bit,
-                Err(what) => {
-                    eprintln!("{}", what);
-                    eprintln!("Usage: {} [-c <config file>]", crate::PKG_NAME);
-                    std::process::exit(1)
-                }
+                Err(what) => usage(&what),
             })
-            .collect::<Arguments>()
+            .collect::<Arguments>();
+
+        match args.command {
+            Command::Serve if args.json || args.repair => {
+                usage("--json and --repair only go with check")
+            }
+            _ => args,
+        }
     }
 }
@@ -0,0 +1,323 @@
+//! Rules for what usernames, display names and email addresses may look like.
+use crate::settings::{Charset, Names};
+use unicode_normalization::UnicodeNormalization;
+
+/// Longest email address there can be, as far as SMTP is concerned.
+const MAX_EMAIL_SIZE: usize = 254;
+
+/// The form usernames are compared in. Names that only differ in case, or in
+/// how their characters happen to be encoded, fold into the same string.
+pub fn fold(username: &str) -> String {
+    username
+        .nfkc()
+        .flat_map(char::to_lowercase)
+        .nfkc()
+        .collect()
+}
+
+/// Checks a username against the policy, handing back its normalized form.
+pub fn username(policy: &Names, raw: &str) -> Result<String, String> {
+    let name = raw.nfc().collect::<String>();
+
+    let length = name.chars().count();
+    if length < policy.min_username_length {
+        return Err(format!(
+            "must be at least {} characters long",
+            policy.min_username_length
+        ));
+    }
+    if length > policy.max_username_length {
+        return Err(format!(
+            "must be at most {} characters long",
+            policy.max_username_length
+        ));
+    }
+    if let Some(c) = name.chars().find(|&c| !username_char(policy, c)) {
+        return Err(format!("must not contain {:?}", c));
+    }
+
+    let folded = fold(&name);
+    if policy
+        .reserved
+        .iter()
+        .any(|reserved| fold(reserved) == folded)
+    {
+        return Err("is reserved".to_owned());
+    }
+
+    Ok(name)
+}
+
+/// Checks a display name against the policy, handing back its normalized
+/// form, with runs of whitespace squeezed into single spaces.
+pub fn display_name(policy: &Names, raw: &str) -> Result<String, String> {
+    let name = raw.nfc().collect::<String>();
+    if name.chars().any(|c| c.is_control() || invisible(c)) {
+        return Err("must not contain control or invisible characters".to_owned());
+    }
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    if name.is_empty() {
+        return Err("must not be empty".to_owned());
+    }
+
+    let length = name.chars().count();
+    if length < policy.min_name_length {
+        return Err(format!(
+            "must be at least {} characters long",
+            policy.min_name_length
+        ));
+    }
+    if length > policy.max_name_length {
+        return Err(format!(
+            "must be at most {} characters long",
+            policy.max_name_length
+        ));
+    }
+
+    Ok(name)
+}
+
+/// Checks that an email address at least looks like one, handing it back with
+/// surrounding whitespace trimmed. Whether it actually is one is up to the
+/// verification mailed to it.
+pub fn email(raw: &str) -> Result<String, String> {
+    let address = raw.trim();
+    if address.len() > MAX_EMAIL_SIZE {
+        return Err(format!("must be at most {} bytes long", MAX_EMAIL_SIZE));
+    }
+    if address
+        .chars()
+        .any(|c| c.is_whitespace() || c.is_control() || "<>(),;:\\\"[]".contains(c))
+    {
+        return Err("is not a valid email address".to_owned());
+    }
+
+    let mut parts = address.rsplitn(2, '@');
+    let (domain, local) = match (parts.next(), parts.next()) {
+        (Some(domain), Some(local)) => (domain, local),
+        _ => return Err("is not a valid email address".to_owned()),
+    };
+    let domain_ok = domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !domain.contains("..");
+    if local.is_empty() || local.contains('@') || !domain_ok {
+        return Err("is not a valid email address".to_owned());
+    }
+
+    Ok(address.to_owned())
+}
+
+fn username_char(policy: &Names, c: char) -> bool {
+    /* Colons delimit the parts of our database keys. */
+    if c == ':' || c.is_whitespace() || c.is_control() {
+        return false;
+    }
+    let alphanumeric = match policy.username_charset {
+        Charset::Ascii => c.is_ascii_alphanumeric(),
+        Charset::Unicode => c.is_alphanumeric(),
+    };
+    alphanumeric || policy.username_symbols.contains(c)
+}
+
+/// Zero-width and bidirectional formatting characters, which would let a
+/// name pass for a different one.
+fn invisible(c: char) -> bool {
+    match c {
+        '\u{200b}'..='\u{200f}'
+        | '\u{202a}'..='\u{202e}'
+        | '\u{2060}'..='\u{2064}'
+        | '\u{2066}'..='\u{2069}'
+        | '\u{feff}' => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unicode() -> Names {
+        Names {
+            username_charset: Charset::Unicode,
+            ..Names::default()
+        }
+    }
+
+    #[test]
+    fn folds_case_and_compatibility_forms() {
+        assert_eq!(fold("Alice"), "alice");
+        assert_eq!(fold("ＡＬＩＣＥ"), "alice");
+        assert_eq!(fold("ﬁle"), "file");
+        /* Precomposed and combining spellings of the same letter. */
+        assert_eq!(fold("Jos\u{e9}"), fold("JOSE\u{301}"));
+        assert_ne!(fold("alice"), fold("alicia"));
+    }
+
+    #[test]
+    fn limits_username_length() {
+        let policy = Names::default();
+        assert!(username(&policy, &"a".repeat(policy.min_username_length - 1)).is_err());
+        assert!(username(&policy, &"a".repeat(policy.min_username_length)).is_ok());
+        assert!(username(&policy, &"a".repeat(policy.max_username_length)).is_ok());
+        assert!(username(&policy, &"a".repeat(policy.max_username_length + 1)).is_err());
+        /* Characters count, not bytes. */
+        let policy = unicode();
+        assert!(username(&unicode(), &"é".repeat(policy.max_username_length)).is_ok());
+    }
+
+    #[test]
+    fn keeps_usernames_to_their_charset() {
+        let ascii = Names::default();
+        assert_eq!(username(&ascii, "alice.b_c-1").unwrap(), "alice.b_c-1");
+        assert!(username(&ascii, "josé").is_err());
+        assert!(username(&ascii, "alice!").is_err());
+
+        assert_eq!(username(&unicode(), "josé").unwrap(), "josé");
+        assert_eq!(username(&unicode(), "ジョアン").unwrap(), "ジョアン");
+        assert!(username(&unicode(), "alice!").is_err());
+        /* Handed back composed, however it was typed. */
+        assert_eq!(username(&unicode(), "jose\u{301}").unwrap(), "jos\u{e9}");
+    }
+
+    #[test]
+    fn rejects_colons_whitespace_and_controls_in_usernames() {
+        let mut policy = unicode();
+        policy.username_symbols = ": \t\u{7}._-".to_owned();
+        for name in &[
+            "ali:ce",
+            "ali ce",
+            "ali\tce",
+            "ali\u{7}ce",
+            "alice\n",
+            "ali\u{a0}ce",
+        ] {
+            assert!(username(&policy, name).is_err(), "{:?} passed", name);
+        }
+    }
+
+    #[test]
+    fn rejects_reserved_usernames_however_spelled() {
+        let policy = unicode();
+        for name in &["admin", "ADMIN", "Root", "ａｄｍｉｎ", "ＳＹＳＴＥＭ"] {
+            assert_eq!(username(&policy, name), Err("is reserved".to_owned()));
+        }
+        assert!(username(&policy, "admin2").is_ok());
+    }
+
+    #[test]
+    fn squeezes_whitespace_in_display_names() {
+        let policy = Names::default();
+        assert_eq!(
+            display_name(&policy, "  Alice \u{a0}  B.\u{3000}Smith ").unwrap(),
+            "Alice B. Smith"
+        );
+        assert!(display_name(&policy, " \u{a0} ").is_err());
+    }
+
+    #[test]
+    fn limits_display_name_length() {
+        let policy = Names::default();
+        assert!(display_name(&policy, &"a".repeat(policy.max_name_length)).is_ok());
+        assert!(display_name(&policy, &"a".repeat(policy.max_name_length + 1)).is_err());
+
+        let policy = Names {
+            min_name_length: 3,
+            ..Names::default()
+        };
+        assert!(display_name(&policy, "Al").is_err());
+        /* Whitespace that gets squeezed out doesn't count. */
+        assert!(display_name(&policy, "Al     ").is_err());
+        assert_eq!(display_name(&policy, "A     l").unwrap(), "A l");
+    }
+
+    #[test]
+    fn rejects_invisible_characters_in_display_names() {
+        let policy = Names::default();
+        for name in &[
+            "Ali\u{200b}ce",
+            "Alice\u{200d}",
+            "\u{202e}ecilA",
+            "Ali\u{2066}ce",
+            "\u{feff}Alice",
+            "Ali\u{7}ce",
+            "Alice\r\nBob",
+        ] {
+            assert!(display_name(&policy, name).is_err(), "{:?} passed", name);
+        }
+        assert_eq!(
+            display_name(&policy, "Zoë Ångström").unwrap(),
+            "Zoë Ångström"
+        );
+    }
+
+    fn assert_invalid(raw: &str) {
+        if let Ok(address) = email(raw) {
+            panic!("{:?} passed for an email address as {:?}", raw, address);
+        }
+    }
+
+    #[test]
+    fn accepts_plain_addresses() {
+        assert_eq!(email("alice@example.com").unwrap(), "alice@example.com");
+        assert_eq!(
+            email("alice.b+bank@mail.example.co.uk").unwrap(),
+            "alice.b+bank@mail.example.co.uk"
+        );
+    }
+
+    #[test]
+    fn trims_surrounding_whitespace() {
+        assert_eq!(
+            email("  alice@example.com\t\n").unwrap(),
+            "alice@example.com"
+        );
+    }
+
+    #[test]
+    fn rejects_missing_at() {
+        assert_invalid("alice.example.com");
+        assert_invalid("");
+    }
+
+    #[test]
+    fn rejects_empty_local_part() {
+        assert_invalid("@example.com");
+    }
+
+    #[test]
+    fn rejects_malformed_domains() {
+        assert_invalid("alice@localhost");
+        assert_invalid("alice@");
+        assert_invalid("alice@.example.com");
+        assert_invalid("alice@example.com.");
+        assert_invalid("alice@example..com");
+    }
+
+    #[test]
+    fn rejects_more_than_one_at() {
+        assert_invalid("alice@bob@example.com");
+    }
+
+    #[test]
+    fn rejects_header_injection() {
+        assert_invalid("alice@example.com\r\nBcc: eve@example.com");
+        assert_invalid("alice@example.com\nBcc:eve@example.com");
+        assert_invalid("alice@exa\rmple.com");
+        assert_invalid("alice@example.com,eve@example.com");
+        assert_invalid("Alice <alice@example.com>");
+        assert_invalid("alice smith@example.com");
+    }
+
+    #[test]
+    fn limits_length() {
+        let domain = "@example.com";
+        let longest = "a".repeat(MAX_EMAIL_SIZE - domain.len()) + domain;
+        assert_eq!(email(&longest).unwrap(), longest);
+
+        let longer = "a".repeat(MAX_EMAIL_SIZE + 1 - domain.len()) + domain;
+        assert!(email(&longer).is_err());
+        /* Only what's left once trimmed counts. */
+        assert!(email(&format!("  {}  ", longest)).is_ok());
+    }
+}
@@ -44,30 +44,326 @@ impl Default for Logging {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SigningAlgorithm {
+    HS256,
+    HS384,
+    HS512,
+    RS256,
+    ES256,
+}
+
+/// A public key tokens signed elsewhere are accepted from, such as a key that
+/// is being rotated out.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct VerificationKey {
+    /// Id tokens signed by this key carry in their `kid` header.
+    pub id: String,
+    pub algorithm: SigningAlgorithm,
+    /// PEM file holding the public key.
+    pub public_key: PathBuf,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase", default)]
 pub struct Auth {
-    pub algorithm: jwt::Algorithm,
-    pub secret: String,
+    pub algorithm: SigningAlgorithm,
+    /// Shared secret tokens are signed with under the HMAC algorithms. A random
+    /// one is made up when left out, which logs everyone out on every restart.
+    pub secret: Option<String>,
+    /// PEM file holding the private key tokens are signed with under RS256 and
+    /// ES256.
+    pub private_key: Option<PathBuf>,
+    /// Id of the signing key. Derived from the public key when left out.
+    pub key_id: Option<String>,
+    pub verification_keys: Vec<VerificationKey>,
+    /// How long, in seconds, an access token stays valid for.
+    pub access_lifetime: u64,
+    /// How long, in seconds, a session may go without being refreshed.
+    pub refresh_lifetime: u64,
+    /// How long, in seconds, the token mailed to verify an email address is
+    /// valid for.
+    pub verification_lifetime: u64,
+    /// How long, in seconds, the token mailed to reset a key is valid for.
+    pub reset_lifetime: u64,
 }
 impl Default for Auth {
     fn default() -> Auth {
-        use rand::distributions::Alphanumeric;
-        use rand::{thread_rng, Rng};
-        use std::iter;
-
-        let mut rng = thread_rng();
         Auth {
-            algorithm: jwt::Algorithm::HS256,
-            secret: iter::repeat(())
-                .map(|()| rng.sample(Alphanumeric))
-                .take(32)
-                .collect(),
+            algorithm: SigningAlgorithm::HS256,
+            secret: None,
+            private_key: None,
+            key_id: None,
+            verification_keys: Vec::new(),
+            access_lifetime: 900,
+            refresh_lifetime: 1209600,
+            verification_lifetime: 86400,
+            reset_lifetime: 3600,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct TwoFactor {
+    /// Name we go by in authenticator apps.
+    pub issuer: String,
+    /// Whether users holding any privileges must enable two-factor
+    /// authentication before they get to use them.
+    pub enforce_for_admins: bool,
+    /// Number of recovery codes handed out on enrollment.
+    pub recovery_codes: usize,
+    /// How long, in seconds, a login waits for its second factor.
+    pub challenge_lifetime: u64,
+}
+impl Default for TwoFactor {
+    fn default() -> TwoFactor {
+        TwoFactor {
+            issuer: crate::PKG_NAME.to_owned(),
+            enforce_for_admins: false,
+            recovery_codes: 10,
+            challenge_lifetime: 300,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum HashAlgorithm {
+    BCrypt,
+    Argon2id,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct KeyHash {
+    /// Algorithm new keyhashes are generated with. Keyhashes generated with any
+    /// other algorithm or parameters get upgraded the next time their owner
+    /// logs in.
+    pub algorithm: HashAlgorithm,
+    /// BCrypt cost.
+    pub cost: u32,
+    /// Argon2id memory cost, in KiB.
+    pub memory: u32,
+    /// Argon2id number of passes over the memory.
+    pub iterations: u32,
+    /// Argon2id degree of parallelism.
+    pub parallelism: u32,
+}
+impl Default for KeyHash {
+    fn default() -> KeyHash {
+        KeyHash {
+            algorithm: HashAlgorithm::Argon2id,
+            cost: bcrypt::DEFAULT_COST,
+            memory: 19456,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+/// How failed logins are throttled. Past the free attempts, each failure locks
+/// logins out for twice as long as the one before it did.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct Lockout {
+    pub enabled: bool,
+    /// Failed logins into a username allowed before locking it out.
+    pub free_attempts: u32,
+    /// Failed logins from an address allowed before locking it out.
+    pub ip_free_attempts: u32,
+    /// Length of the first lockout, in seconds.
+    pub base_delay: u64,
+    /// Length of the longest lockout, in seconds.
+    pub max_delay: u64,
+    /// How long, in seconds, failed logins are remembered for.
+    pub window: u64,
+}
+impl Default for Lockout {
+    fn default() -> Lockout {
+        Lockout {
+            enabled: true,
+            free_attempts: 5,
+            ip_free_attempts: 20,
+            base_delay: 1,
+            max_delay: 900,
+            window: 86400,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MailTransport {
+    /// Hand mail over to a relay, in plain SMTP.
+    Smtp,
+    /// Write mail to files in a directory instead of sending it anywhere.
+    Outbox,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct Mail {
+    pub transport: MailTransport,
+    /// Address mail is sent from.
+    pub from: String,
+    /// Relay mail is handed over to under the SMTP transport.
+    pub smtp_address: String,
+    /// Credentials to log in to the relay with. The connection to the relay
+    /// isn't encrypted, so they're only ever sent to one on this machine,
+    /// unless `smtp_insecure` says otherwise.
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    /// Whether to send the credentials to a relay on another machine anyway,
+    /// in the clear for anyone in between to read.
+    pub smtp_insecure: bool,
+    /// Directory mail is written to under the outbox transport.
+    pub outbox_directory: PathBuf,
+    /// Link mailed for verifying an email address, which the token gets
+    /// appended to. Without one, the bare token is mailed instead.
+    pub verification_url: Option<String>,
+    /// Link mailed for resetting a key, which the token gets appended to.
+    /// Without one, the bare token is mailed instead.
+    pub reset_url: Option<String>,
+}
+impl Default for Mail {
+    fn default() -> Mail {
+        Mail {
+            transport: MailTransport::Outbox,
+            from: "joao@localhost".to_owned(),
+            smtp_address: "127.0.0.1:25".to_owned(),
+            smtp_username: None,
+            smtp_password: None,
+            smtp_insecure: false,
+            outbox_directory: PathBuf::from("./outbox/"),
+            verification_url: None,
+            reset_url: None,
+        }
+    }
+}
+
+/// Which letters and digits usernames may be made of.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Charset {
+    /// Only the ASCII ones.
+    Ascii,
+    /// Those of any script.
+    Unicode,
+}
+
+/// What usernames and display names may look like.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct Names {
+    pub min_username_length: usize,
+    pub max_username_length: usize,
+    pub username_charset: Charset,
+    /// Symbols usernames may have besides letters and digits. Whitespace and
+    /// colons are never allowed, whatever is in here.
+    pub username_symbols: String,
+    pub min_name_length: usize,
+    pub max_name_length: usize,
+    /// Usernames nobody may register, regardless of case.
+    pub reserved: Vec<String>,
+}
+impl Default for Names {
+    fn default() -> Names {
+        Names {
+            min_username_length: 3,
+            max_username_length: 32,
+            username_charset: Charset::Ascii,
+            username_symbols: "._-".to_owned(),
+            min_name_length: 1,
+            max_name_length: 64,
+            reserved: vec![
+                "admin".to_owned(),
+                "administrator".to_owned(),
+                "root".to_owned(),
+                "system".to_owned(),
+                "support".to_owned(),
+            ],
+        }
+    }
+}
+
+/// Something a role may allow its holders to do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Permission {
+    /// Look at other people's accounts.
+    ReadAccounts,
+    /// Put money into accounts.
+    Deposit,
+    /// Take money out of other people's accounts.
+    Withdraw,
+    /// Grant and revoke roles.
+    ManageRoles,
+    /// Clear login lockouts.
+    ManageLockouts,
+    /// Freeze and unfreeze accounts.
+    Freeze,
+}
+impl Permission {
+    pub fn all() -> BTreeSet<Permission> {
+        [
+            Permission::ReadAccounts,
+            Permission::Deposit,
+            Permission::Withdraw,
+            Permission::ManageRoles,
+            Permission::ManageLockouts,
+            Permission::Freeze,
+        ]
+        .iter()
+        .cloned()
+        .collect()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct Role {
+    pub permissions: BTreeSet<Permission>,
+    /// Largest single deposit holders of this role may make, if any.
+    pub deposit_limit: Option<u32>,
+}
+impl Default for Role {
+    fn default() -> Role {
+        Role {
+            permissions: BTreeSet::new(),
+            deposit_limit: None,
         }
     }
 }
 
-use std::collections::BTreeMap;
+fn default_roles() -> BTreeMap<String, Role> {
+    let mut roles = BTreeMap::new();
+    roles.insert(
+        "auditor".to_owned(),
+        Role {
+            permissions: [Permission::ReadAccounts].iter().cloned().collect(),
+            deposit_limit: None,
+        },
+    );
+    roles.insert(
+        "teller".to_owned(),
+        Role {
+            permissions: [Permission::ReadAccounts, Permission::Deposit]
+                .iter()
+                .cloned()
+                .collect(),
+            deposit_limit: Some(1000),
+        },
+    );
+    roles.insert(
+        "superadmin".to_owned(),
+        Role {
+            permissions: Permission::all(),
+            deposit_limit: None,
+        },
+    );
+
+    roles
+}
+
+use std::collections::{BTreeMap, BTreeSet};
 use std::net::{SocketAddr, ToSocketAddrs};
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase", default)]
@@ -77,10 +373,19 @@ pub struct Settings {
     pub database_id: u8,
     pub workers: u16,
     pub keep_alive: u32,
+    /// Whether to take client addresses from the X-Real-IP header, as set by
+    /// a reverse proxy in front of us.
+    pub trust_real_ip: bool,
     pub size_limits: BTreeMap<String, u64>,
     pub logging: Logging,
     pub filesystem_logger: FilesystemLogger,
     pub auth: Auth,
+    pub key_hash: KeyHash,
+    pub two_factor: TwoFactor,
+    pub lockout: Lockout,
+    pub names: Names,
+    pub mail: Mail,
+    pub roles: BTreeMap<String, Role>,
 }
 impl Default for Settings {
     fn default() -> Settings {
@@ -90,10 +395,17 @@ impl Default for Settings {
             database_id: 0,
             workers: 4,
             keep_alive: 0,
+            trust_real_ip: false,
             size_limits: BTreeMap::new(),
             logging: Default::default(),
             filesystem_logger: Default::default(),
             auth: Default::default(),
+            key_hash: Default::default(),
+            two_factor: Default::default(),
+            lockout: Default::default(),
+            names: Default::default(),
+            mail: Default::default(),
+            roles: default_roles(),
         }
     }
 }
@@ -1,9 +1,15 @@
+use crate::keyhash::PasswordHasher;
+use crate::mail::Mailer;
 use crate::pool::Pool;
 use crate::settings::Settings;
+use crate::signing::Keys;
 use redis::Connection;
 
 pub struct Server {
     pub settings: Settings,
     pub db_conn: Pool<Connection>,
+    pub hasher: Box<dyn PasswordHasher>,
+    pub keys: Keys,
+    pub mailer: Box<dyn Mailer>,
 }
 impl Server {}
@@ -0,0 +1,210 @@
+//! Time-based one-time passwords, as described by RFC 6238, along with the
+//! recovery codes that stand in for them when the authenticator is lost.
+use ring::{digest, hmac};
+
+/// Size of the generated secrets, in bytes.
+const SECRET_SIZE: usize = 20;
+/// Length of time each code is valid for, in seconds.
+const STEP: u64 = 30;
+/// Number of steps around the current one whose codes are still accepted, to
+/// make up for clocks drifting apart.
+const SKEW: u64 = 1;
+/// Number of digits in a code.
+const DIGITS: usize = 6;
+/// Size of a recovery code, in bytes, before encoding.
+const RECOVERY_CODE_SIZE: usize = 5;
+
+const ALPHABET: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+
+/// Generates a new secret, encoded in base32 the way authenticators want it.
+pub fn generate_secret() -> String {
+    let secret = (0..SECRET_SIZE)
+        .map(|_| rand::random::<u8>())
+        .collect::<Vec<_>>();
+
+    base32::encode(ALPHABET, &secret[..])
+}
+
+/// Builds the URI authenticator apps take the secret in through, usually by
+/// way of a QR code.
+pub fn provisioning_uri(issuer: &str, account: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        percent_encode(issuer),
+        percent_encode(account),
+        secret,
+        percent_encode(issuer),
+        DIGITS,
+        STEP
+    )
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|c| match c {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (c as char).to_string()
+            }
+            _ => format!("%{:02X}", c),
+        })
+        .collect()
+}
+
+/// HOTP value of the secret for the given step, as per RFC 4226.
+fn code_at(secret: &[u8], step: u64) -> u32 {
+    let key = hmac::SigningKey::new(&digest::SHA1, secret);
+    let mac = hmac::sign(&key, &step.to_be_bytes());
+    let mac = mac.as_ref();
+
+    let offset = (mac[mac.len() - 1] & 0x0f) as usize;
+    let binary = (u32::from(mac[offset]) & 0x7f) << 24
+        | u32::from(mac[offset + 1]) << 16
+        | u32::from(mac[offset + 2]) << 8
+        | u32::from(mac[offset + 3]);
+
+    binary % 10u32.pow(DIGITS as u32)
+}
+
+/// Checks the code against the secret at the given Unix time. Returns the step
+/// the code belongs to, which should be kept around so that the same code
+/// can't be used twice.
+pub fn verify(secret: &str, code: &str, now: u64) -> Option<u64> {
+    let secret = base32::decode(ALPHABET, secret)?;
+    if code.len() != DIGITS || !code.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let code = code.parse::<u32>().ok()?;
+
+    let current = now / STEP;
+    (current.saturating_sub(SKEW)..=current + SKEW).find(|&step| code_at(&secret[..], step) == code)
+}
+
+/// Generates a batch of recovery codes.
+pub fn generate_recovery_codes(count: usize) -> Vec<String> {
+    (0..count)
+        .map(|_| {
+            let code = (0..RECOVERY_CODE_SIZE)
+                .map(|_| rand::random::<u8>())
+                .collect::<Vec<_>>();
+
+            base32::encode(ALPHABET, &code[..])
+        })
+        .collect()
+}
+
+/// Hashes a recovery code for storage. Recovery codes are random enough for a
+/// plain digest to do, unlike keys.
+pub fn hash_recovery_code(code: &str) -> String {
+    let code = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect::<String>();
+
+    digest::digest(&digest::SHA256, code.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|val| format!("{:02x}", val))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Secret the test vectors of RFC 6238 are computed with, for SHA-1.
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    /// Times and codes from Appendix B of RFC 6238, cut down to our digits.
+    const RFC_VECTORS: &[(u64, &str)] = &[
+        (59, "287082"),
+        (1_111_111_109, "081804"),
+        (1_111_111_111, "050471"),
+        (1_234_567_890, "005924"),
+        (2_000_000_000, "279037"),
+        (20_000_000_000, "353130"),
+    ];
+
+    fn rfc_secret() -> String {
+        base32::encode(ALPHABET, RFC_SECRET)
+    }
+
+    #[test]
+    fn matches_rfc_6238_vectors() {
+        for &(time, code) in RFC_VECTORS {
+            assert_eq!(
+                format!("{:06}", code_at(RFC_SECRET, time / STEP)),
+                code,
+                "at {}",
+                time
+            );
+            assert_eq!(verify(&rfc_secret(), code, time), Some(time / STEP));
+        }
+    }
+
+    #[test]
+    fn accepts_codes_within_skew() {
+        let (time, code) = RFC_VECTORS[3];
+        let step = time / STEP;
+        for offset in 0..=SKEW {
+            assert_eq!(
+                verify(&rfc_secret(), code, time + offset * STEP),
+                Some(step)
+            );
+            assert_eq!(
+                verify(&rfc_secret(), code, time - offset * STEP),
+                Some(step)
+            );
+        }
+        assert_eq!(verify(&rfc_secret(), code, time + (SKEW + 1) * STEP), None);
+        assert_eq!(verify(&rfc_secret(), code, time - (SKEW + 1) * STEP), None);
+    }
+
+    #[test]
+    fn accepts_codes_near_the_epoch() {
+        /* Steps before the first one don't exist, rather than wrapping around. */
+        let code = format!("{:06}", code_at(RFC_SECRET, 0));
+        assert_eq!(verify(&rfc_secret(), &code, 0), Some(0));
+        assert_eq!(verify(&rfc_secret(), &code, STEP), Some(0));
+    }
+
+    #[test]
+    fn rejects_malformed_codes() {
+        let (time, code) = RFC_VECTORS[0];
+        assert_eq!(verify(&rfc_secret(), &code[1..], time), None);
+        assert_eq!(verify(&rfc_secret(), &format!("{}0", code), time), None);
+        assert_eq!(verify(&rfc_secret(), "+87082", time), None);
+        assert_eq!(verify(&rfc_secret(), " 87082", time), None);
+        assert_eq!(verify(&rfc_secret(), "", time), None);
+        assert_eq!(verify("not base32!", code, time), None);
+    }
+
+    #[test]
+    fn secrets_round_trip_through_base32() {
+        let secret = generate_secret();
+        assert!(secret
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || ('2'..='7').contains(&c)));
+
+        let raw = base32::decode(ALPHABET, &secret).unwrap();
+        assert_eq!(raw.len(), SECRET_SIZE);
+        assert_eq!(base32::encode(ALPHABET, &raw), secret);
+        assert_eq!(
+            base32::decode(ALPHABET, &rfc_secret()).unwrap(),
+            RFC_SECRET.to_vec()
+        );
+    }
+
+    #[test]
+    fn recovery_codes_hash_regardless_of_formatting() {
+        let code = &generate_recovery_codes(1)[0];
+        let hash = hash_recovery_code(code);
+        assert_eq!(hash_recovery_code(&code.to_lowercase()), hash);
+        assert_eq!(
+            hash_recovery_code(&format!(" {}-{} ", &code[..4], &code[4..])),
+            hash
+        );
+        assert_ne!(hash_recovery_code(&code[1..]), hash);
+    }
+}